@@ -47,3 +47,55 @@ grep -cri "the" tests/inputs >"$OUT_DIR/the.recursive.insensitive.count"
 
 # STDIN, insensitive, count
 cat tests/inputs/*.txt | grep -ci the - >"$OUT_DIR/the.recursive.insensitive.count.stdin"
+
+# Line number, byte offset
+grep -n "The" tests/inputs/bustle.txt >"$OUT_DIR/bustle.txt.the.capitalized.line_number"
+grep -b "The" tests/inputs/bustle.txt >"$OUT_DIR/bustle.txt.the.capitalized.byte_offset"
+grep -nbi "the" $DIR/*.txt >"$OUT_DIR/all.the.lowercase.insensitive.line_number.byte_offset"
+
+# Context
+grep -A1 "the" tests/inputs/bustle.txt >"$OUT_DIR/bustle.txt.the.lowercase.after_context"
+grep -B2 "The" tests/inputs/bustle.txt >"$OUT_DIR/bustle.txt.the.capitalized.before_context"
+grep -nC1 "The" $DIR/*.txt >"$OUT_DIR/all.the.capitalized.context.line_number"
+grep -A0 "The" tests/inputs/bustle.txt >"$OUT_DIR/bustle.txt.the.capitalized.after_context.zero"
+grep -onC1 "The" $DIR/*.txt >"$OUT_DIR/all.the.capitalized.only_matching.context.line_number"
+grep -onvC1 "the" $DIR/*.txt >"$OUT_DIR/all.the.lowercase.only_matching.invert.context.line_number"
+
+# Only matching
+grep -o "the" tests/inputs/bustle.txt >"$OUT_DIR/bustle.txt.the.lowercase.only_matching"
+grep -onbi "the" $DIR/*.txt >"$OUT_DIR/all.the.lowercase.insensitive.only_matching.line_number.byte_offset"
+
+# Color
+grep --color=always -n -C1 "the" $DIR/*.txt >"$OUT_DIR/all.the.lowercase.context.line_number.color"
+grep --color=always -v -C1 "the" tests/inputs/bustle.txt >"$OUT_DIR/bustle.txt.the.lowercase.invert.context.color"
+GREP_COLORS="ms=04;32:fn=34:ne" grep --color=always -o "the" $DIR/*.txt >"$OUT_DIR/all.the.lowercase.only_matching.grep_colors"
+GREP_COLORS="sl=1:cx=2:ms=04:mc=05" grep --color=always -n -C1 "the" $DIR/*.txt >"$OUT_DIR/all.the.lowercase.context.line_number.grep_colors_lines"
+
+# Files with/without matches
+grep -l "The" $DIR/*.txt >"$OUT_DIR/all.the.capitalized.files_with_matches"
+grep -L "The" $DIR/*.txt >"$OUT_DIR/all.the.capitalized.files_without_match"
+grep -rlv "the" tests/inputs >"$OUT_DIR/the.recursive.invert.files_with_matches"
+
+# Fixed strings
+grep -F -on "." tests/inputs/bustle.txt >"$OUT_DIR/bustle.txt.dot.fixed_strings"
+grep -Fi "nobody!" tests/inputs/nobody.txt >"$OUT_DIR/nobody.txt.fixed_strings.insensitive"
+grep -Fv "$(printf 'the\nThe')" $DIR/*.txt >"$OUT_DIR/all.the.fixed_strings.invert"
+
+# Multiple patterns
+grep -e "fox" -e "Frog" $DIR/*.txt >"$OUT_DIR/all.fox.frog.regexp"
+grep -f tests/patterns/the_fox.txt $DIR/*.txt >"$OUT_DIR/all.the_fox.pattern_file"
+
+# Whole words, whole lines
+LC_ALL=C.UTF-8 grep -w "the" $DIR/*.txt >"$OUT_DIR/all.the.lowercase.word_regexp"
+LC_ALL=C.UTF-8 grep -wo "Nobody" tests/inputs/nobody.txt >"$OUT_DIR/nobody.txt.word_regexp.only_matching"
+grep -xi "the bustle in a house" $DIR/*.txt >"$OUT_DIR/all.bustle.line_regexp"
+grep "dog.$" tests/inputs/fox.txt >"$OUT_DIR/fox.txt.dog.end_of_line"
+
+# Include/exclude globs
+grep -r --include="f*.txt" "dog" tests/inputs >"$OUT_DIR/dog.recursive.include"
+grep --exclude="*.txt" "The" $DIR/*.txt >"$OUT_DIR/all.the.capitalized.exclude" || true
+grep -r --exclude-dir="inputs" "dog" tests/inputs >"$OUT_DIR/dog.recursive.exclude_dir" || true
+
+# Recursive, sorted by path
+grep -rn "the" tests/inputs | sort -s -t: -k1,1 >"$OUT_DIR/the.recursive.line_number.sorted"
+grep -rn -C1 "the" $DIR/bustle.txt $DIR/empty.txt $DIR/fox.txt $DIR/nobody.txt >"$OUT_DIR/the.recursive.line_number.context.sorted"
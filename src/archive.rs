@@ -0,0 +1,187 @@
+use crate::decompress::Compression;
+use anyhow::Result;
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek},
+    path::Path,
+};
+use zip::ZipArchive;
+
+/// `--archives` で中のファイルを検索するアーカイブの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    /// 圧縮されていればその形式を持つtar
+    Tar(Option<Compression>),
+    Zip,
+}
+
+impl ArchiveKind {
+    /// `.tar`、`.tar.gz` や `.tgz` のような圧縮されたtar、`.zip` を拡張子から判別する
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(OsStr::to_str)? {
+            "zip" => Some(Self::Zip),
+            "tar" => Some(Self::Tar(None)),
+            "tgz" | "tbz2" | "txz" => Some(Self::Tar(Compression::from_path(path))),
+            _ => {
+                let compression = Compression::from_path(path)?;
+                let stem = Path::new(path.file_stem()?);
+                (stem.extension() == Some(OsStr::new("tar")))
+                    .then_some(Self::Tar(Some(compression)))
+            }
+        }
+    }
+}
+
+/// アーカイブの読み込み元
+pub enum Source<'a> {
+    File(File),
+    /// 別のアーカイブの中のファイル
+    Member(&'a mut dyn Read),
+}
+
+impl<'a> Source<'a> {
+    fn into_reader(self) -> Box<dyn Read + 'a> {
+        match self {
+            Self::File(file) => Box::new(file),
+            Self::Member(member) => Box::new(member),
+        }
+    }
+}
+
+/// アーカイブ内の通常のファイルを、アーカイブ内のパスとともに先頭から順に `f` へ渡す。
+/// `f` が `false` を返すとそこで打ち切る
+pub fn for_each_member<F>(kind: ArchiveKind, source: Source, f: F) -> Result<()>
+where
+    F: FnMut(&str, &mut dyn Read) -> Result<bool>,
+{
+    match (kind, source) {
+        (ArchiveKind::Tar(None), source) => tar_members(source.into_reader(), f),
+        (ArchiveKind::Tar(Some(compression)), source) => {
+            let reader = BufReader::new(source.into_reader());
+            tar_members(compression.decoder(Box::new(reader))?, f)
+        }
+        (ArchiveKind::Zip, Source::File(file)) => zip_members(file, f),
+        // 中のzipはシークできないので、メモリに読み込む
+        (ArchiveKind::Zip, Source::Member(member)) => {
+            let mut buf = vec![];
+            member.read_to_end(&mut buf)?;
+            zip_members(Cursor::new(buf), f)
+        }
+    }
+}
+
+/// tarの通常のファイルを先頭から順に `f` へ渡す
+fn tar_members<R, F>(reader: R, mut f: F) -> Result<()>
+where
+    R: Read,
+    F: FnMut(&str, &mut dyn Read) -> Result<bool>,
+{
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let path = entry.path()?.display().to_string();
+        if !f(&path, &mut entry)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// zipのファイルを先頭から順に `f` へ渡す。末尾の目録を読むため、シークできる必要がある
+fn zip_members<R, F>(reader: R, mut f: F) -> Result<()>
+where
+    R: Read + Seek,
+    F: FnMut(&str, &mut dyn Read) -> Result<bool>,
+{
+    let mut archive = ZipArchive::new(reader)?;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        let path = file.name().to_string();
+        if !f(&path, &mut file)? {
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tar_members, zip_members, ArchiveKind};
+    use crate::decompress::Compression;
+    use std::{
+        io::{Cursor, Write},
+        path::Path,
+    };
+    use zip::{write::FileOptions, ZipWriter};
+
+    #[test]
+    fn test_from_path() {
+        let kind = |path: &str| ArchiveKind::from_path(Path::new(path));
+        assert_eq!(kind("a.tar"), Some(ArchiveKind::Tar(None)));
+        assert_eq!(
+            kind("a.tar.gz"),
+            Some(ArchiveKind::Tar(Some(Compression::Gzip)))
+        );
+        assert_eq!(
+            kind("a.tgz"),
+            Some(ArchiveKind::Tar(Some(Compression::Gzip)))
+        );
+        assert_eq!(kind("a.zip"), Some(ArchiveKind::Zip));
+        assert_eq!(kind("a.log.gz"), None);
+        assert_eq!(kind("a.txt"), None);
+    }
+
+    #[test]
+    fn test_members() {
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, text) in [("a.txt", "foo\n"), ("dir/b.txt", "bar\n")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(text.len() as u64);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, text.as_bytes())
+                .unwrap();
+        }
+        let data = builder.into_inner().unwrap();
+        let mut members = vec![];
+        tar_members(&data[..], |path, member| {
+            let mut text = String::new();
+            member.read_to_string(&mut text)?;
+            members.push((path.to_string(), text));
+            Ok(true)
+        })
+        .unwrap();
+        assert_eq!(
+            members,
+            vec![
+                ("a.txt".to_string(), "foo\n".to_string()),
+                ("dir/b.txt".to_string(), "bar\n".to_string())
+            ]
+        );
+
+        // falseを返すと以降のファイルを読まない
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        writer
+            .add_directory("dir/", FileOptions::default())
+            .unwrap();
+        for path in ["dir/a.txt", "dir/b.txt"] {
+            writer.start_file(path, FileOptions::default()).unwrap();
+            writer.write_all(b"foo\n").unwrap();
+        }
+        let data = writer.finish().unwrap().into_inner();
+        let mut paths = vec![];
+        zip_members(Cursor::new(data), |path, _| {
+            paths.push(path.to_string());
+            Ok(false)
+        })
+        .unwrap();
+        assert_eq!(paths, vec!["dir/a.txt"]);
+    }
+}
@@ -0,0 +1,151 @@
+use std::env;
+
+/// `GREP_COLORS` と同じ形式で指定できる出力の色
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Colors {
+    pub selected_match: String,
+    pub context_match: String,
+    pub selected_line: String,
+    pub context_line: String,
+    pub filename: String,
+    pub line_number: String,
+    pub byte_offset: String,
+    pub separator: String,
+    erase_line: bool,
+}
+
+impl Default for Colors {
+    fn default() -> Self {
+        Self {
+            selected_match: "01;31".to_string(),
+            context_match: "01;31".to_string(),
+            selected_line: String::new(),
+            context_line: String::new(),
+            filename: "35".to_string(),
+            line_number: "32".to_string(),
+            byte_offset: "32".to_string(),
+            separator: "36".to_string(),
+            erase_line: true,
+        }
+    }
+}
+
+impl Colors {
+    /// 色を付けない設定
+    pub fn none() -> Self {
+        Self {
+            selected_match: String::new(),
+            context_match: String::new(),
+            selected_line: String::new(),
+            context_line: String::new(),
+            filename: String::new(),
+            line_number: String::new(),
+            byte_offset: String::new(),
+            separator: String::new(),
+            erase_line: false,
+        }
+    }
+
+    /// `GREPR_COLORS`、なければ `GREP_COLORS` を既定の色に適用する
+    pub fn from_env() -> Self {
+        let mut colors = Self::default();
+        if let Ok(spec) = env::var("GREPR_COLORS").or_else(|_| env::var("GREP_COLORS")) {
+            colors.apply(&spec);
+        }
+        colors
+    }
+
+    /// `ms=01;31:fn=35:ne` のような指定を適用する。未知の項目は無視する
+    pub fn apply(&mut self, spec: &str) {
+        for cap in spec.split(':') {
+            let (name, value) = cap.split_once('=').unwrap_or((cap, ""));
+            let value = value.to_string();
+            match name {
+                "mt" => {
+                    self.selected_match = value.clone();
+                    self.context_match = value;
+                }
+                "ms" => self.selected_match = value,
+                "mc" => self.context_match = value,
+                "sl" => self.selected_line = value,
+                "cx" => self.context_line = value,
+                "fn" => self.filename = value,
+                "ln" => self.line_number = value,
+                "bn" => self.byte_offset = value,
+                "se" => self.separator = value,
+                "ne" => self.erase_line = false,
+                _ => {}
+            }
+        }
+    }
+
+    /// `sgr` が空でなければ `text` をエスケープシーケンスで囲む
+    pub fn paint(&self, sgr: &str, text: &str) -> String {
+        String::from_utf8(self.paint_bytes(sgr, text.as_bytes())).unwrap()
+    }
+
+    /// `paint` と同じく着色する。`text` はUTF-8でなくてもよい
+    pub fn paint_bytes(&self, sgr: &str, text: &[u8]) -> Vec<u8> {
+        if sgr.is_empty() || text.is_empty() {
+            return text.to_vec();
+        }
+        let mut painted = self.start(sgr).into_bytes();
+        painted.extend_from_slice(text);
+        painted.extend_from_slice(self.end(sgr).as_bytes());
+        painted
+    }
+
+    /// `sgr` の着色を始めるエスケープシーケンス。`sgr` が空なら空文字列を返す
+    pub fn start(&self, sgr: &str) -> String {
+        if sgr.is_empty() {
+            return String::new();
+        }
+        let el = if self.erase_line { "\x1b[K" } else { "" };
+        format!("\x1b[{sgr}m{el}")
+    }
+
+    /// `start` で始めた着色を終えるエスケープシーケンス
+    fn end(&self, sgr: &str) -> String {
+        if sgr.is_empty() {
+            return String::new();
+        }
+        let el = if self.erase_line { "\x1b[K" } else { "" };
+        format!("\x1b[m{el}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Colors;
+
+    #[test]
+    fn test_apply() {
+        let mut colors = Colors::default();
+        colors.apply("mt=04:fn=34:ln=:unknown=1:ne");
+        assert_eq!(colors.selected_match, "04");
+        assert_eq!(colors.context_match, "04");
+        assert_eq!(colors.filename, "34");
+        assert_eq!(colors.line_number, "");
+        assert_eq!(colors.separator, "36");
+
+        // neを指定すると行末の消去を出力しない
+        assert_eq!(colors.paint("34", "foo"), "\x1b[34mfoo\x1b[m");
+        assert_eq!(colors.paint("", "foo"), "foo");
+    }
+
+    #[test]
+    fn test_paint() {
+        let colors = Colors::default();
+        assert_eq!(
+            colors.paint(&colors.selected_match, "foo"),
+            "\x1b[01;31m\x1b[Kfoo\x1b[m\x1b[K"
+        );
+        assert_eq!(Colors::none().paint("", "foo"), "foo");
+
+        // 不正なUTF-8のバイトもそのまま囲む
+        assert_eq!(
+            colors.paint_bytes("32", b"\xff"),
+            b"\x1b[32m\x1b[K\xff\x1b[m\x1b[K"
+        );
+    }
+}
@@ -0,0 +1,120 @@
+use anyhow::Result;
+use bzip2::bufread::MultiBzDecoder;
+use flate2::bufread::MultiGzDecoder;
+use std::{
+    ffi::OsStr,
+    io::{BufRead, Read},
+    path::Path,
+};
+use xz2::bufread::XzDecoder;
+
+/// `-z` で展開しながら検索する圧縮形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Bzip2,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// 拡張子から圧縮形式を判別する
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension().and_then(OsStr::to_str)? {
+            "gz" | "tgz" => Some(Self::Gzip),
+            "bz2" | "tbz2" => Some(Self::Bzip2),
+            "xz" | "txz" => Some(Self::Xz),
+            "zst" | "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    /// 先頭のマジックナンバーから圧縮形式を判別する
+    pub fn from_magic(buf: &[u8]) -> Option<Self> {
+        if buf.starts_with(b"\x1f\x8b") {
+            Some(Self::Gzip)
+        } else if buf.starts_with(b"BZh") {
+            Some(Self::Bzip2)
+        } else if buf.starts_with(b"\xfd7zXZ\x00") {
+            Some(Self::Xz)
+        } else if buf.starts_with(b"\x28\xb5\x2f\xfd") {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    /// `reader` を展開しながら読む。連結された複数のストリームも続けて読む
+    pub fn decoder<'a>(self, reader: Box<dyn BufRead + 'a>) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::Gzip => Box::new(MultiGzDecoder::new(reader)),
+            Self::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+            Self::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+            Self::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        })
+    }
+}
+
+/// 圧縮形式の拡張子を除いたファイル名。圧縮されていなければそのまま返す
+pub fn strip_extension(name: &OsStr) -> &OsStr {
+    let path = Path::new(name);
+    match (Compression::from_path(path), path.file_stem()) {
+        (Some(_), Some(stem)) => stem,
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{strip_extension, Compression};
+    use flate2::{write::GzEncoder, Compression as Level};
+    use std::{
+        ffi::OsStr,
+        io::{BufReader, Read, Write},
+        path::Path,
+    };
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(
+            Compression::from_path(Path::new("app.log.gz")),
+            Some(Compression::Gzip)
+        );
+        assert_eq!(
+            Compression::from_path(Path::new("app.log.zst")),
+            Some(Compression::Zstd)
+        );
+        assert_eq!(Compression::from_path(Path::new("app.log")), None);
+
+        assert_eq!(
+            Compression::from_magic(b"BZh91AY&SY"),
+            Some(Compression::Bzip2)
+        );
+        assert_eq!(
+            Compression::from_magic(b"\xfd7zXZ\x00\x00"),
+            Some(Compression::Xz)
+        );
+        assert_eq!(Compression::from_magic(b"plain text"), None);
+
+        assert_eq!(strip_extension(OsStr::new("app.log.gz")), "app.log");
+        assert_eq!(strip_extension(OsStr::new("app.log")), "app.log");
+    }
+
+    #[test]
+    fn test_decoder() {
+        // 連結されたgzipのストリームを続けて展開する
+        let mut data = vec![];
+        for text in ["foo\n", "bar\n"] {
+            let mut encoder = GzEncoder::new(vec![], Level::default());
+            encoder.write_all(text.as_bytes()).unwrap();
+            data.extend(encoder.finish().unwrap());
+        }
+        assert_eq!(Compression::from_magic(&data), Some(Compression::Gzip));
+        let mut decoder = Compression::Gzip
+            .decoder(Box::new(BufReader::new(&data[..])))
+            .unwrap();
+        let mut text = String::new();
+        decoder.read_to_string(&mut text).unwrap();
+        assert_eq!(text, "foo\nbar\n");
+    }
+}
@@ -1,132 +1,1508 @@
+mod archive;
+mod color;
+mod decompress;
+mod matcher;
+mod printer;
+mod rewrite;
+mod types;
+
 use anyhow::{anyhow, Result};
-use clap::Parser;
-use regex::{Regex, RegexBuilder};
+use archive::{ArchiveKind, Source};
+use clap::{Parser, ValueEnum};
+use color::Colors;
+use decompress::Compression;
+use encoding_rs::Encoding;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{
+    overrides::{Override, OverrideBuilder},
+    types::Types,
+    WalkBuilder, WalkState,
+};
+use matcher::{Matcher, MatcherBuilder};
+use memmap2::Mmap;
+use printer::Summary;
+use rewrite::WriteOptions;
 use std::{
+    collections::VecDeque,
+    env,
+    ffi::OsStr,
     fs::{self, File},
-    io::{self, BufRead, BufReader},
-    mem, vec,
+    io::{self, BufRead, BufReader, IsTerminal, Read, Seek, SeekFrom, Write},
+    iter::Peekable,
+    ops::Range,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread, vec,
 };
-use walkdir::WalkDir;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
 pub struct Args {
-    #[arg(help = "Search pattern")]
-    pattern: String,
+    #[arg(
+        help = "Search pattern",
+        required_unless_present_any = ["regexp", "pattern_files", "type_list"]
+    )]
+    pattern: Option<String>,
 
-    #[arg(value_name = "FILE", help = "Input file(s)", default_value = "-")]
+    #[arg(value_name = "FILE", help = "Input file(s) [default: -]")]
     files: Vec<String>,
 
+    #[arg(
+        short = 'e',
+        long,
+        value_name = "PATTERN",
+        help = "Use PATTERN for matching (can be repeated)"
+    )]
+    regexp: Vec<String>,
+
+    #[arg(
+        short = 'f',
+        long = "file",
+        value_name = "FILE",
+        help = "Take patterns from FILE, one per line"
+    )]
+    pattern_files: Vec<String>,
+
     #[arg(short, long, help = "Case insensitive")]
     insensitive: bool,
 
     #[arg(short, long, help = "Recursive search")]
     recursive: bool,
 
+    #[arg(long, help = "Search hidden files and directories")]
+    hidden: bool,
+
+    #[arg(long, help = "Don't respect .gitignore, .ignore and .grepignore files")]
+    no_ignore: bool,
+
+    #[arg(long, help = "Don't respect .gitignore and git exclude files")]
+    no_ignore_vcs: bool,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Search only files whose base name matches GLOB"
+    )]
+    include: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Skip files whose base name matches GLOB"
+    )]
+    exclude: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "GLOB",
+        help = "Skip directories whose base name matches GLOB"
+    )]
+    exclude_dir: Vec<String>,
+
+    #[arg(
+        short = 'g',
+        long = "glob",
+        value_name = "GLOB",
+        help = "Include or exclude (with !) files matching GLOB; later globs take precedence"
+    )]
+    globs: Vec<String>,
+
+    #[arg(
+        short = 't',
+        long = "type",
+        value_name = "TYPE",
+        help = "Search only files of TYPE"
+    )]
+    types: Vec<String>,
+
+    #[arg(
+        short = 'T',
+        long = "type-not",
+        value_name = "TYPE",
+        help = "Don't search files of TYPE"
+    )]
+    types_not: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "NAME:GLOB",
+        help = "Add a glob to file type NAME (e.g. 'web:*.html')"
+    )]
+    type_add: Vec<String>,
+
+    #[arg(long, help = "Show all supported file types and their globs")]
+    type_list: bool,
+
+    #[arg(
+        short = 'j',
+        long,
+        value_name = "NUM",
+        default_value_t = 1,
+        help = "Search with NUM threads (0 means the number of CPUs)"
+    )]
+    threads: usize,
+
+    #[arg(
+        long,
+        value_name = "SORTBY",
+        value_enum,
+        help = "Sort results (searches with a single thread)"
+    )]
+    sort: Option<SortBy>,
+
     #[arg(short, long, help = "Count occurrences")]
     count: bool,
 
+    #[arg(short = 'F', long, help = "Interpret the pattern as fixed strings")]
+    fixed_strings: bool,
+
+    #[arg(short = 'w', long, help = "Match only whole words")]
+    word_regexp: bool,
+
+    #[arg(short = 'x', long, help = "Match only whole lines")]
+    line_regexp: bool,
+
+    #[arg(
+        short = 'U',
+        long,
+        conflicts_with_all = ["only_matching", "replace"],
+        help = "Search each file as a whole so that matches can span lines"
+    )]
+    multiline: bool,
+
+    #[arg(
+        long,
+        requires = "multiline",
+        help = "Make '.' match newlines with --multiline"
+    )]
+    multiline_dotall: bool,
+
+    #[arg(
+        long,
+        help = "Match bytes instead of Unicode characters (e.g. '\\xFF' matches the byte 0xFF)"
+    )]
+    no_unicode: bool,
+
     #[arg(short = 'v', long, help = "Invert match")]
     invert_match: bool,
+
+    #[arg(
+        short = 'm',
+        long,
+        value_name = "NUM",
+        help = "Stop reading a file after NUM selected lines (on unix, a seekable stdin is left just after the last match)"
+    )]
+    max_count: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "TYPE",
+        value_enum,
+        default_value_t = BinaryFiles::Binary,
+        help = "How to handle files that look binary"
+    )]
+    binary_files: BinaryFiles,
+
+    #[arg(short = 'a', long, help = "Process a binary file as if it were text")]
+    text: bool,
+
+    #[arg(short = 'I', help = "Skip files that look binary")]
+    skip_binary: bool,
+
+    #[arg(
+        short = 'E',
+        long,
+        value_name = "ENCODING",
+        help = "Decode input as ENCODING (e.g. shift_jis, euc-jp, latin1) instead of detecting a BOM"
+    )]
+    encoding: Option<String>,
+
+    #[arg(
+        short = 'z',
+        long,
+        help = "Search in gzip, bzip2, xz and zstd compressed files"
+    )]
+    search_zip: bool,
+
+    #[arg(long, help = "Search files inside tar, tar.gz and zip archives")]
+    archives: bool,
+
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value_t = 3,
+        help = "Open archives nested up to NUM levels inside another (0: don't open nested archives)"
+    )]
+    archive_depth: usize,
+
+    #[arg(
+        long,
+        overrides_with = "no_mmap",
+        help = "Search regular files through a memory map"
+    )]
+    mmap: bool,
+
+    #[arg(long, overrides_with = "mmap", help = "Never use memory maps")]
+    no_mmap: bool,
+
+    #[arg(short, long, help = "Suppress all normal output; stop on first match")]
+    quiet: bool,
+
+    #[arg(
+        short = 's',
+        long,
+        help = "Suppress error messages about unreadable files"
+    )]
+    no_messages: bool,
+
+    #[arg(
+        short = 'l',
+        long,
+        conflicts_with = "files_without_match",
+        help = "Print only names of files with selected lines"
+    )]
+    files_with_matches: bool,
+
+    #[arg(
+        short = 'L',
+        long,
+        help = "Print only names of files with no selected lines"
+    )]
+    files_without_match: bool,
+
+    #[arg(short = 'n', long, help = "Print line number with output lines")]
+    line_number: bool,
+
+    #[arg(short = 'b', long, help = "Print the byte offset with output lines")]
+    byte_offset: bool,
+
+    #[arg(long, help = "Print the column number of the first match (implies -n)")]
+    column: bool,
+
+    #[arg(short, long, help = "Print only the matched parts of a line")]
+    only_matching: bool,
+
+    #[arg(
+        short = 'p',
+        long,
+        value_name = "TEMPLATE",
+        help = "Print matches replaced with TEMPLATE, expanding $1, ${name} and $$"
+    )]
+    replace: Option<String>,
+
+    #[arg(
+        long,
+        requires = "replace",
+        conflicts_with_all = [
+            "invert_match",
+            "encoding",
+            "search_zip",
+            "archives",
+            "json",
+            "count",
+            "quiet",
+            "files_with_matches",
+            "files_without_match",
+            "only_matching",
+            "line_number",
+            "byte_offset",
+            "column",
+            "after_context",
+            "before_context",
+            "context",
+            "color"
+        ],
+        help = "Rewrite files in place with matches replaced by the --replace template"
+    )]
+    write: bool,
+
+    #[arg(
+        long,
+        value_name = "SUFFIX",
+        requires = "write",
+        help = "Keep each rewritten file's original as FILE + SUFFIX"
+    )]
+    backup: Option<String>,
+
+    #[arg(
+        long,
+        requires = "write",
+        help = "Print a unified diff of the changes instead of rewriting files"
+    )]
+    dry_run: bool,
+
+    #[arg(
+        short = 'A',
+        long,
+        value_name = "NUM",
+        help = "Print NUM lines of trailing context"
+    )]
+    after_context: Option<usize>,
+
+    #[arg(
+        short = 'B',
+        long,
+        value_name = "NUM",
+        help = "Print NUM lines of leading context"
+    )]
+    before_context: Option<usize>,
+
+    #[arg(
+        short = 'C',
+        long,
+        value_name = "NUM",
+        help = "Print NUM lines of output context"
+    )]
+    context: Option<usize>,
+
+    #[arg(
+        long,
+        value_name = "WHEN",
+        value_enum,
+        default_value_t = ColorChoice::Never,
+        default_missing_value = "auto",
+        num_args = 0..=1,
+        require_equals = true,
+        help = "Highlight matches, file names, line numbers and separators"
+    )]
+    color: ColorChoice,
+
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "count",
+            "quiet",
+            "files_with_matches",
+            "files_without_match",
+            "only_matching",
+            "replace"
+        ],
+        help = "Print results as JSON Lines"
+    )]
+    json: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum BinaryFiles {
+    /// 一致したら「Binary file X matches」とだけ出力する
+    Binary,
+    /// テキストとして扱う
+    Text,
+    /// 一致しないものとして扱う
+    WithoutMatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SortBy {
+    Path,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
 }
 
 #[derive(Debug)]
 pub struct Config {
-    pattern: Regex,
+    pattern: Matcher,
     files: Vec<String>,
     recursive: bool,
+    walk: WalkOptions,
+    type_list: bool,
+    threads: usize,
     count: bool,
     invert_match: bool,
+    max_count: Option<usize>,
+    multiline: bool,
+    binary_files: BinaryFiles,
+    encoding: Option<&'static Encoding>,
+    search_zip: bool,
+    archives: bool,
+    archive_depth: usize,
+    mmap: bool,
+    quiet: bool,
+    no_messages: bool,
+    files_with_matches: bool,
+    files_without_match: bool,
+    line_number: bool,
+    byte_offset: bool,
+    column: bool,
+    only_matching: bool,
+    replace: Option<Vec<u8>>,
+    write: Option<WriteOptions>,
+    /// -A、-B、-Cのいずれかが指定されていれば、その行数
+    context: Option<Context>,
+    colors: Colors,
+    json: bool,
+}
+
+impl Config {
+    /// 一致した行の前後の文脈行と区切りを出力するか。
+    /// grepと同じく、行数が0でも指定されていれば区切りを出力し、-oでは文脈行の中身を出力しない
+    fn has_context(&self) -> bool {
+        self.context.is_some()
+            && !(self.count
+                || self.quiet
+                || self.files_with_matches
+                || self.files_without_match
+                || self.write.is_some())
+    }
+}
+
+/// 再帰的な検索でどのファイルを辿るか
+#[derive(Debug, Default, Clone)]
+struct WalkOptions {
+    hidden: bool,
+    no_ignore: bool,
+    no_ignore_vcs: bool,
+    include: GlobSet,
+    exclude: GlobSet,
+    exclude_dir: GlobSet,
+    overrides: Option<Override>,
+    types: Option<Types>,
+    sort_by_path: bool,
+    search_zip: bool,
+    archives: bool,
+}
+
+impl WalkOptions {
+    /// --include、--excludeで選ばれるファイル名か。
+    /// -zのときは `app.log.gz` を `app.log` としても照合する。
+    /// --archivesのときは中のファイルを--includeで選ぶため、アーカイブは--excludeだけで除く
+    fn is_included_file(&self, name: Option<&OsStr>) -> bool {
+        name.map_or(true, |name| {
+            let is_match = |set: &GlobSet| {
+                set.is_match(name)
+                    || self.search_zip && set.is_match(decompress::strip_extension(name))
+            };
+            let is_archive = self.archives && ArchiveKind::from_path(Path::new(name)).is_some();
+            !is_match(&self.exclude)
+                && (self.include.is_empty() || is_archive || is_match(&self.include))
+        })
+    }
+
+    /// --exclude-dirで除かれるディレクトリ名か
+    fn is_excluded_dir(&self, name: Option<&OsStr>) -> bool {
+        name.is_some_and(|name| self.exclude_dir.is_match(name))
+    }
+
+    fn builder(&self, path: &str) -> WalkBuilder {
+        let vcs = !self.no_ignore && !self.no_ignore_vcs;
+        let mut builder = WalkBuilder::new(path);
+        builder
+            .hidden(!self.hidden)
+            .parents(!self.no_ignore)
+            .ignore(!self.no_ignore)
+            .git_ignore(vcs)
+            .git_global(vcs)
+            .git_exclude(vcs);
+        if !self.no_ignore {
+            builder.add_custom_ignore_filename(".grepignore");
+        }
+        if let Some(overrides) = &self.overrides {
+            builder.overrides(overrides.clone());
+        }
+        if let Some(types) = &self.types {
+            builder.types(types.clone());
+        }
+        if self.sort_by_path {
+            builder.sort_by_file_path(|a, b| a.cmp(b));
+        }
+        // 除くディレクトリはその中を辿らないよう、走査の途中で取り除く
+        let walk = self.clone();
+        builder.filter_entry(move |entry| {
+            if entry.depth() == 0 {
+                return true;
+            }
+            match entry.file_type() {
+                Some(file_type) if file_type.is_dir() => {
+                    !walk.is_excluded_dir(Some(entry.file_name()))
+                }
+                _ => walk.is_included_file(Some(entry.file_name())),
+            }
+        });
+        builder
+    }
+}
+
+fn build_glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    Ok(builder.build()?)
+}
+
+/// ripgrepの-gと同じく、`!` で始まるものは除外として、後のものほど優先して適用する
+fn build_overrides(globs: &[String]) -> Result<Option<Override>> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = OverrideBuilder::new(env::current_dir()?);
+    for glob in globs {
+        builder.add(glob)?;
+    }
+    Ok(Some(builder.build()?))
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Context {
+    before: usize,
+    after: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum LineKind {
+    Match,
+    Context,
+}
+
+#[derive(Debug)]
+struct Line<'a> {
+    kind: LineKind,
+    number: usize,
+    offset: u64,
+    text: &'a [u8],
+    /// --multilineで、行をまたぐ一致のうちこの行にかかる部分の行の先頭からの範囲。
+    /// `None` なら出力するときに行ごとに検索する
+    matches: Option<Vec<Range<usize>>>,
 }
 
 pub fn get_args() -> Result<Config> {
-    let args = Args::parse();
-    let pattern = &args.pattern;
-    let pattern = RegexBuilder::new(pattern)
+    let mut args = Args::parse();
+    // パターンの出どころとパターンの組
+    let mut patterns: Vec<(Option<String>, String)> = vec![];
+    if args.regexp.is_empty() && args.pattern_files.is_empty() {
+        patterns.extend(args.pattern.take().map(|pattern| (None, pattern)));
+    } else {
+        // -eや-fがあれば、最初の位置引数もファイルとして扱う
+        args.files.splice(0..0, args.pattern.take());
+        for (i, pattern) in args.regexp.iter().enumerate() {
+            patterns.push((Some(format!("-e #{}", i + 1)), pattern.to_string()));
+        }
+        for filename in &args.pattern_files {
+            let text = match filename.as_str() {
+                "-" => io::read_to_string(io::stdin()),
+                _ => fs::read_to_string(filename),
+            }
+            .map_err(|err| anyhow!("{filename}: {err}"))?;
+            for (i, pattern) in text.lines().enumerate() {
+                patterns.push((Some(format!("{filename}:{}", i + 1)), pattern.to_string()));
+            }
+        }
+    }
+    if args.files.is_empty() {
+        args.files.push("-".to_string());
+    }
+
+    let mut types = types::types_builder(&args.type_add)?;
+    for name in &args.types {
+        types.select(name);
+    }
+    for name in &args.types_not {
+        types.negate(name);
+    }
+    let types = types.build()?;
+
+    let mut builder = MatcherBuilder::new();
+    builder
         .case_insensitive(args.insensitive)
-        .build()
-        .map_err(|_| anyhow!("Invalid pattern \"{pattern}\""))?;
+        .fixed_strings(args.fixed_strings)
+        .word(args.word_regexp)
+        .line(args.line_regexp)
+        .no_unicode(args.no_unicode)
+        .dot_matches_new_line(args.multiline_dotall);
+    let pattern = if args.fixed_strings {
+        // grepと同じく改行で区切られた複数の文字列のいずれかを探す
+        let patterns: Vec<_> = patterns
+            .iter()
+            .flat_map(|(_, pattern)| pattern.split('\n'))
+            .collect();
+        builder.build(&patterns)?
+    } else {
+        // どのパターンが不正なのかを示すため、1つずつ検証する
+        for (location, pattern) in &patterns {
+            if let Err(err) = builder.build(&[pattern]) {
+                let location = location
+                    .as_ref()
+                    .map_or(String::new(), |location| format!("{location}: "));
+                return Err(anyhow!("{location}Invalid pattern \"{pattern}\"\n{err}"));
+            }
+        }
+        let patterns: Vec<_> = patterns
+            .iter()
+            .map(|(_, pattern)| pattern.as_str())
+            .collect();
+        builder.build(&patterns)?
+    };
     Ok(Config {
         pattern,
         files: args.files,
         recursive: args.recursive,
+        walk: WalkOptions {
+            hidden: args.hidden,
+            no_ignore: args.no_ignore,
+            no_ignore_vcs: args.no_ignore_vcs,
+            include: build_glob_set(&args.include)?,
+            exclude: build_glob_set(&args.exclude)?,
+            exclude_dir: build_glob_set(&args.exclude_dir)?,
+            overrides: build_overrides(&args.globs)?,
+            types: Some(types),
+            sort_by_path: args.sort == Some(SortBy::Path),
+            search_zip: args.search_zip,
+            archives: args.archives,
+        },
+        type_list: args.type_list,
+        // 並べ替えるときは1つのスレッドで順に検索する
+        threads: match (args.sort, args.threads) {
+            (Some(_), _) => 1,
+            (None, 0) => thread::available_parallelism().map_or(1, |n| n.get()),
+            (None, n) => n,
+        },
         count: args.count,
         invert_match: args.invert_match,
+        max_count: args.max_count,
+        multiline: args.multiline,
+        binary_files: if args.text {
+            BinaryFiles::Text
+        } else if args.skip_binary {
+            BinaryFiles::WithoutMatch
+        } else {
+            args.binary_files
+        },
+        encoding: args
+            .encoding
+            .map(|label| {
+                Encoding::for_label(label.as_bytes())
+                    .ok_or_else(|| anyhow!("{label}: Unknown encoding"))
+            })
+            .transpose()?,
+        search_zip: args.search_zip,
+        archives: args.archives,
+        archive_depth: args.archive_depth,
+        mmap: args.mmap && !args.no_mmap,
+        quiet: args.quiet,
+        no_messages: args.no_messages,
+        files_with_matches: args.files_with_matches,
+        files_without_match: args.files_without_match,
+        line_number: args.line_number || args.column,
+        byte_offset: args.byte_offset,
+        column: args.column,
+        only_matching: args.only_matching,
+        replace: args.replace.map(String::into_bytes),
+        write: args.write.then_some(WriteOptions {
+            backup: args.backup,
+            dry_run: args.dry_run,
+        }),
+        context: (args.before_context.is_some()
+            || args.after_context.is_some()
+            || args.context.is_some())
+        .then_some(Context {
+            before: args.before_context.or(args.context).unwrap_or(0),
+            after: args.after_context.or(args.context).unwrap_or(0),
+        }),
+        colors: match args.color {
+            ColorChoice::Always => Colors::from_env(),
+            ColorChoice::Auto
+                if io::stdout().is_terminal() && env::var("TERM").map_or(true, |t| t != "dumb") =>
+            {
+                Colors::from_env()
+            }
+            _ => Colors::none(),
+        },
+        json: args.json,
     })
 }
 
-pub fn run(config: Config) -> Result<()> {
-    let entries = find_files(&config.files, config.recursive);
-    let num_files = entries.len();
-    let print = |filename: &str, value: &str| {
-        if num_files > 1 {
-            print!("{filename}:{value}");
-        } else {
-            print!("{value}");
+/// --type-listなら種類の一覧を出力し、そうでなければファイルを検索して結果を出力する。
+/// -jが1でなければ並列に検索し、--jsonなら最後に全体の集計を出力して、終了コードを返す
+pub fn run(config: Config) -> Result<i32> {
+    if config.type_list {
+        for def in config.walk.types.iter().flat_map(Types::definitions) {
+            println!("{}: {}", def.name(), def.globs().join(", "));
+        }
+        return Ok(0);
+    }
+    // grepと同じく、ディレクトリを再帰的に検索するときは見つかったファイルが1つでもファイル名を出力する
+    let with_filename = config.files.len() > 1
+        || config.recursive && config.files.iter().any(|path| Path::new(path).is_dir());
+    let matched = AtomicBool::new(false);
+    let had_error = AtomicBool::new(false);
+    let summary = Summary::default();
+    let report = |err: String| {
+        had_error.store(true, Ordering::Relaxed);
+        if !config.no_messages {
+            eprintln!("{err}");
         }
     };
-    for entry in entries {
-        match entry {
-            Err(err) => eprintln!("{err}"),
-            Ok(filename) => match open(&filename) {
-                Err(err) => eprintln!("{filename}: {err}"),
-                Ok(file) => match find_lines(file, &config.pattern, config.invert_match) {
-                    Err(err) => eprintln!("{err}"),
-                    Ok(matches) => {
-                        if config.count {
-                            print(&filename, &format!("{}\n", matches.len()));
-                        } else {
-                            for line in &matches {
-                                print(&filename, line);
+    if config.threads == 1 {
+        let mut printed = false;
+        let mut out = io::stdout().lock();
+        for entry in find_files(&config.files, config.recursive, &config.walk) {
+            match entry {
+                Err(err) => report(format!("{err}")),
+                Ok(filename) => {
+                    match search_file(&config, &filename, with_filename, printed, &mut out) {
+                        Ok(count) => {
+                            summary.add(count);
+                            if count > 0 {
+                                matched.store(true, Ordering::Relaxed);
+                                printed = true;
+                                if config.quiet {
+                                    break;
+                                }
                             }
                         }
+                        Err(err) if is_broken_pipe(&err) => break,
+                        Err(err) => report(format!("{filename}: {err}")),
                     }
-                },
-            },
+                }
+            }
+        }
+    } else {
+        // ファイルごとの出力をまとめてから書き込み、他のファイルの出力と混ざらないようにする
+        let printed = Mutex::new(false);
+        let search = |filename: &str| {
+            let mut buf = vec![];
+            match search_file(&config, filename, with_filename, false, &mut buf) {
+                Ok(count) => {
+                    summary.add(count);
+                    if !buf.is_empty() {
+                        let mut printed = printed.lock().unwrap();
+                        let mut out = io::stdout().lock();
+                        let result = if config.has_context() && !config.json && *printed {
+                            let colors = &config.colors;
+                            writeln!(out, "{}", colors.paint(&colors.separator, "--"))
+                        } else {
+                            Ok(())
+                        }
+                        .and_then(|_| out.write_all(&buf));
+                        if result.is_err() {
+                            return WalkState::Quit;
+                        }
+                        *printed = true;
+                    }
+                    if count > 0 {
+                        matched.store(true, Ordering::Relaxed);
+                        if config.quiet {
+                            return WalkState::Quit;
+                        }
+                    }
+                }
+                Err(err) if is_broken_pipe(&err) => return WalkState::Quit,
+                Err(err) => report(format!("{filename}: {err}")),
+            }
+            WalkState::Continue
+        };
+        let mut roots = vec![];
+        for operand in find_operands(&config.files, config.recursive, &config.walk) {
+            match operand {
+                Err(err) => report(format!("{err}")),
+                // 標準入力は走査できないので、先に検索する
+                Ok(path) if path == "-" => {
+                    if search(&path) == WalkState::Quit {
+                        return Ok(exit_code(&config, &matched, &had_error));
+                    }
+                }
+                Ok(path) => roots.push(path),
+            }
+        }
+        if let Some((first, rest)) = roots.split_first() {
+            let mut builder = config.walk.builder(first);
+            for root in rest {
+                builder.add(root);
+            }
+            builder.threads(config.threads).build_parallel().run(|| {
+                Box::new(|entry| match entry {
+                    Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => {
+                        search(&entry.path().display().to_string())
+                    }
+                    _ => WalkState::Continue,
+                })
+            });
         }
     }
-    Ok(())
+    if config.json {
+        match summary.write_json(&mut io::stdout().lock()) {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(exit_code(&config, &matched, &had_error))
 }
 
-fn open(filename: &str) -> Result<Box<dyn BufRead>> {
-    Ok(match filename {
-        "-" => Box::new(BufReader::new(io::stdin())),
-        _ => Box::new(BufReader::new(File::open(filename)?)),
-    })
+/// grepと同じく、一致した行があれば0、なければ1、エラーがあれば2を返す
+fn exit_code(config: &Config, matched: &AtomicBool, had_error: &AtomicBool) -> i32 {
+    let matched = matched.load(Ordering::Relaxed);
+    let had_error = had_error.load(Ordering::Relaxed);
+    if matched && (config.quiet || !had_error) {
+        0
+    } else if had_error {
+        2
+    } else {
+        1
+    }
+}
+
+fn is_broken_pipe(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<io::Error>()
+        .is_some_and(|err| err.kind() == io::ErrorKind::BrokenPipe)
 }
 
-fn find_lines<T: BufRead>(mut file: T, pattern: &Regex, invert_match: bool) -> Result<Vec<String>> {
-    let mut matches = vec![];
-    let mut buf = String::new();
-    while file.read_line(&mut buf)? > 0 {
-        if pattern.is_match(&buf) ^ invert_match {
-            matches.push(mem::take(&mut buf));
+/// 1つのファイルを検索して結果を `out` に書き込み、一致した行数を返す。
+/// --writeなら一致した部分を置き換えてファイルを書き換える。
+/// `separate` が真なら、文脈行を出力するときに最初の行の前にも区切りを書く
+fn search_file(
+    config: &Config,
+    filename: &str,
+    with_filename: bool,
+    separate: bool,
+    out: &mut dyn Write,
+) -> Result<usize> {
+    if let (Some(options), Some(template)) = (&config.write, &config.replace) {
+        return rewrite::rewrite_file(config, filename, template, options, out);
+    }
+    if config.archives {
+        if let Some(kind) = ArchiveKind::from_path(Path::new(filename)) {
+            let file = Source::File(File::open(filename)?);
+            return search_archive(config, filename, kind, file, 0, separate, out);
         }
+    }
+    // GNU grepと同じく、-mで打ち切った標準入力は最後に一致した行の直後に戻し、
+    // 続けて読むプロセスが残りを読めるようにする
+    let stdin_start = match filename {
+        "-" if config.max_count.is_some() => stdin_position(config)?,
+        _ => None,
+    };
+    let file = match mmap(config, filename)? {
+        Some(mmap) => Input::Mapped(mmap),
+        None => Input::Stream(open(filename, config.encoding, config.search_zip)?),
+    };
+    let (count, end) = search_reader(config, filename, file, with_filename, separate, out)?;
+    if let Some(start) = stdin_start {
+        if config.max_count.is_some_and(|max| count >= max) {
+            stdin_file()?.seek(SeekFrom::Start(start + end))?;
+        }
+    }
+    Ok(count)
+}
+
+/// 変換せずに読む標準入力がシークできるファイルなら、その現在の位置を返す。
+/// 標準入力を複製できるunixだけで位置を戻し、他では `None` を返して最後まで読む
+fn stdin_position(config: &Config) -> io::Result<Option<u64>> {
+    if config.encoding.is_some() || config.search_zip {
+        return Ok(None);
+    }
+    // パイプや端末はシークできず、unix以外では複製できないので、そのまま順に読む
+    let Ok(position) = stdin_file().and_then(|mut file| file.stream_position()) else {
+        return Ok(None);
+    };
+    let bom = Encoding::for_bom(io::stdin().lock().fill_buf()?).is_some();
+    Ok((!bom).then_some(position))
+}
+
+/// 標準入力を複製したファイル。読み込む位置は標準入力と共有する
+#[cfg(unix)]
+fn stdin_file() -> io::Result<File> {
+    use std::os::fd::AsFd;
+    Ok(File::from(io::stdin().as_fd().try_clone_to_owned()?))
+}
+
+#[cfg(not(unix))]
+fn stdin_file() -> io::Result<File> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// 検索する入力。通常のファイルはメモリマップしてまとめて検索できる
+enum Input<'a> {
+    Stream(Box<dyn BufRead + 'a>),
+    Mapped(Mmap),
+}
+
+impl Input<'_> {
+    /// バイナリファイルかを判別するための先頭のブロック
+    fn head(&mut self) -> io::Result<&[u8]> {
+        match self {
+            Self::Stream(file) => file.fill_buf(),
+            Self::Mapped(mmap) => Ok(&mmap[..mmap.len().min(8 * 1024)]),
+        }
+    }
+
+    /// --multilineならファイル全体を読み込んでまとめて検索する
+    fn find_lines<F>(self, config: &Config, context: Context, sink: F) -> Result<usize>
+    where
+        F: FnMut(Line) -> Result<bool>,
+    {
+        let pattern = &config.pattern;
+        let invert_match = config.invert_match;
+        let max_count = config.max_count;
+        match (self, config.multiline) {
+            (Self::Stream(file), false) => {
+                find_lines(file, pattern, invert_match, context, max_count, sink)
+            }
+            (Self::Stream(mut file), true) => {
+                let mut buf = vec![];
+                file.read_to_end(&mut buf)?;
+                find_multiline(&buf, pattern, invert_match, context, max_count, sink)
+            }
+            (Self::Mapped(mmap), false) => {
+                find_lines_in_buf(&mmap, pattern, invert_match, context, max_count, sink)
+            }
+            (Self::Mapped(mmap), true) => {
+                find_multiline(&mmap, pattern, invert_match, context, max_count, sink)
+            }
+        }
+    }
+}
+
+/// --mmapのとき、変換せずに読める空でない通常のファイルをメモリマップする。
+/// 標準入力やパイプ、展開や文字コードの変換が必要なファイルは `None` を返し、順に読み込む
+fn mmap(config: &Config, filename: &str) -> Result<Option<Mmap>> {
+    if !config.mmap || filename == "-" || config.encoding.is_some() {
+        return Ok(None);
+    }
+    let file = File::open(filename)?;
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.len() == 0 {
+        return Ok(None);
+    }
+    // 検索中に他のプロセスがファイルを切り詰めるとSIGBUSで終了するが、ripgrepと同じくそれは許容する
+    let mmap = unsafe { Mmap::map(&file)? };
+    let compressed = config.search_zip
+        && (Compression::from_path(Path::new(filename)).is_some()
+            || Compression::from_magic(&mmap).is_some());
+    if compressed || Encoding::for_bom(&mmap).is_some() {
+        return Ok(None);
+    }
+    Ok(Some(mmap))
+}
+
+/// アーカイブ内のファイルを `archive:path` という名前で順に検索し、一致した行数の合計を返す。
+/// `depth` はこのアーカイブが何重に入れ子になっているかで、`config.archive_depth` 重までの中のアーカイブを開く。
+/// それより深いアーカイブはバイト列として検索せずに飛ばす
+fn search_archive(
+    config: &Config,
+    name: &str,
+    kind: ArchiveKind,
+    source: Source,
+    depth: usize,
+    separate: bool,
+    out: &mut dyn Write,
+) -> Result<usize> {
+    let mut count = 0;
+    archive::for_each_member(kind, source, |path, member| {
+        let name = format!("{name}:{path}");
+        let path = Path::new(path);
+        let separate = separate || count > 0;
+        count += match ArchiveKind::from_path(path) {
+            Some(kind) if depth < config.archive_depth => {
+                let member = Source::Member(member);
+                search_archive(config, &name, kind, member, depth + 1, separate, out)?
+            }
+            Some(_) => 0,
+            _ if config.walk.is_included_file(path.file_name()) => {
+                let member = Box::new(BufReader::new(member));
+                let file = decode(&name, member, config.encoding, config.search_zip)?;
+                search_reader(config, &name, Input::Stream(file), true, separate, out)?.0
+            }
+            _ => 0,
+        };
+        Ok(!(config.quiet && count > 0))
+    })?;
+    Ok(count)
+}
+
+/// 開いたファイルを検索して結果を `out` に書き込み、一致した行数と最後に一致した行の終わりの位置を返す
+fn search_reader(
+    config: &Config,
+    filename: &str,
+    mut file: Input,
+    with_filename: bool,
+    separate: bool,
+    out: &mut dyn Write,
+) -> Result<(usize, u64)> {
+    // grepと同じく、最初のブロックにNULがあればバイナリファイルとみなす
+    let binary = config.binary_files != BinaryFiles::Text && file.head()?.contains(&0);
+    if binary && config.binary_files == BinaryFiles::WithoutMatch {
+        return Ok((0, 0));
+    }
+    // -q、-l、-Lとバイナリファイルは最初に一致した行で読み込みを打ち切る。-cとともに行は出力しない
+    let first_only = config.quiet
+        || config.files_with_matches
+        || config.files_without_match
+        || (binary && !config.count);
+    let print_lines = !(first_only || config.count);
+    let context = match config.context {
+        Some(context) if print_lines && config.has_context() => context,
+        _ => Context::default(),
+    };
+    let mut printer = printer::new(config, filename, with_filename, separate);
+    printer.begin(out)?;
+    let mut end = 0;
+    let count = file.find_lines(config, context, |line| {
+        if line.kind == LineKind::Match {
+            end = line.offset + line.text.len() as u64;
+        }
+        if print_lines {
+            printer.line(out, &line)?;
+        }
+        Ok(!first_only)
+    })?;
+    printer.end(out, count, binary)?;
+    Ok((count, end))
+}
+
+fn open(
+    filename: &str,
+    encoding: Option<&'static Encoding>,
+    search_zip: bool,
+) -> Result<Box<dyn BufRead>> {
+    let file: Box<dyn io::Read> = match filename {
+        "-" => Box::new(io::stdin()),
+        _ => Box::new(File::open(filename)?),
+    };
+    decode(
+        filename,
+        Box::new(BufReader::new(file)),
+        encoding,
+        search_zip,
+    )
+}
+
+/// `encoding` が指定されていればそのエンコーディングから、
+/// なければBOMで判別したUTF-16やBOM付きUTF-8からUTF-8に変換して読み込む。
+/// `search_zip` が真なら、拡張子かマジックナンバーで判別した圧縮形式を展開する
+fn decode<'a>(
+    filename: &str,
+    mut file: Box<dyn BufRead + 'a>,
+    encoding: Option<&'static Encoding>,
+    search_zip: bool,
+) -> Result<Box<dyn BufRead + 'a>> {
+    if search_zip {
+        let compression = match Compression::from_path(Path::new(filename)) {
+            Some(compression) => Some(compression),
+            None => Compression::from_magic(file.fill_buf()?),
+        };
+        if let Some(compression) = compression {
+            file = Box::new(BufReader::new(compression.decoder(file)?));
+        }
+    }
+    // BOMがなくエンコーディングも指定されていなければ、バイト列をそのまま読む
+    if encoding.is_none() && Encoding::for_bom(file.fill_buf()?).is_none() {
+        return Ok(Box::new(file));
+    }
+    let decoder = DecodeReaderBytesBuilder::new()
+        .encoding(encoding)
+        .build(file);
+    Ok(Box::new(BufReader::new(decoder)))
+}
+
+/// 一致した行と前後の文脈行を見つかった順に `sink` へ渡し、一致した行数を返す。
+/// `sink` が `false` を返すか、`max_count` 行が一致して後の文脈行を渡し終えるとそこで読み込みを打ち切る
+fn find_lines<T, F>(
+    mut file: T,
+    pattern: &Matcher,
+    invert_match: bool,
+    context: Context,
+    max_count: Option<usize>,
+    mut sink: F,
+) -> Result<usize>
+where
+    T: BufRead,
+    F: FnMut(Line) -> Result<bool>,
+{
+    let mut count = 0;
+    let mut number = 0;
+    let mut offset = 0;
+    let mut buf = vec![];
+    // 直前の行を最大 `context.before` 行まで保持するリングバッファ
+    let mut before: VecDeque<(usize, u64, Vec<u8>)> = VecDeque::with_capacity(context.before);
+    // 一致した行の後に残り何行を文脈行として出力するか
+    let mut after = 0;
+    let max_count = max_count.unwrap_or(usize::MAX);
+    loop {
+        // grepと同じく、上限に達した後の行は一致しても文脈行として渡す
+        if count >= max_count && after == 0 {
+            break;
+        }
+        let bytes = file.read_until(b'\n', &mut buf)?;
+        if bytes == 0 {
+            break;
+        }
+        number += 1;
+        // 行はUTF-8として解釈せず、バイト列のまま検索する
+        let text = &buf[..];
+        // 行末の改行は一致の対象に含めない
+        let line = text.strip_suffix(b"\n").unwrap_or(text);
+        if count < max_count && pattern.is_match(line) ^ invert_match {
+            count += 1;
+            for (number, offset, text) in before.drain(..) {
+                if !sink(Line {
+                    kind: LineKind::Context,
+                    number,
+                    offset,
+                    text: &text,
+                    matches: None,
+                })? {
+                    return Ok(count);
+                }
+            }
+            if !sink(Line {
+                kind: LineKind::Match,
+                number,
+                offset,
+                text,
+                matches: None,
+            })? {
+                return Ok(count);
+            }
+            after = context.after;
+        } else if after > 0 {
+            after -= 1;
+            if !sink(Line {
+                kind: LineKind::Context,
+                number,
+                offset,
+                text,
+                matches: None,
+            })? {
+                return Ok(count);
+            }
+        } else if context.before > 0 {
+            if before.len() == context.before {
+                before.pop_front();
+            }
+            before.push_back((number, offset, text.to_vec()));
+        }
+        offset += bytes as u64;
         buf.clear();
     }
-    Ok(matches)
+    Ok(count)
 }
 
-fn find_files(paths: &[String], recursive: bool) -> Vec<Result<String>> {
+/// `find_lines` と同じく行を `sink` へ渡す。`buf` 全体をまとめて検索し、
+/// 一致した位置の前後だけで行の境界を求める
+fn find_lines_in_buf<F>(
+    buf: &[u8],
+    pattern: &Matcher,
+    invert_match: bool,
+    context: Context,
+    max_count: Option<usize>,
+    sink: F,
+) -> Result<usize>
+where
+    F: FnMut(Line) -> Result<bool>,
+{
+    let selected = SelectedLines {
+        buf,
+        pattern,
+        invert_match,
+        pos: 0,
+        next_match: None,
+    };
+    send_lines(buf, selected, context, max_count, sink)
+}
+
+/// --multilineで `buf` 全体を検索し、一致した部分がかかるすべての行を一致した行として `sink` へ渡す。
+/// `invert_match` なら、どの一致もかからない行を渡す
+fn find_multiline<F>(
+    buf: &[u8],
+    pattern: &Matcher,
+    invert_match: bool,
+    context: Context,
+    max_count: Option<usize>,
+    sink: F,
+) -> Result<usize>
+where
+    F: FnMut(Line) -> Result<bool>,
+{
+    let selected = MultilineLines {
+        buf,
+        matches: pattern.find_iter(buf).peekable(),
+        invert_match,
+        pos: 0,
+        span: None,
+        recent: VecDeque::new(),
+    };
+    send_lines(buf, selected, context, max_count, sink)
+}
+
+/// 先頭から順に選ばれた行の範囲 `selected` を、前後の文脈行とともに `sink` へ渡す
+fn send_lines<I, F>(
+    buf: &[u8],
+    mut selected: I,
+    context: Context,
+    max_count: Option<usize>,
+    mut sink: F,
+) -> Result<usize>
+where
+    I: SelectLines,
+    F: FnMut(Line) -> Result<bool>,
+{
+    let mut count = 0;
+    let mut numbers = LineNumbers::default();
+    let mut send = |selected: &mut I, kind, range: Range<usize>| {
+        sink(Line {
+            kind,
+            number: numbers.at(buf, range.start),
+            offset: range.start as u64,
+            matches: selected.matches_in(&range),
+            text: &buf[range],
+        })
+    };
+    // すでに `sink` へ渡した行の終わり
+    let mut sent = 0;
+    // 一致した行の後に残り何行を文脈行として渡すか
+    let mut after = 0;
+    while count < max_count.unwrap_or(usize::MAX) {
+        let Some(line) = selected.next() else {
+            break;
+        };
+        while after > 0 && sent < line.start {
+            let end = line_end(buf, sent);
+            if !send(&mut selected, LineKind::Context, sent..end)? {
+                return Ok(count);
+            }
+            sent = end;
+            after -= 1;
+        }
+        count += 1;
+        // すでに渡した行と重ならない範囲で、直前の行を最大 `context.before` 行まで遡る
+        let mut start = line.start;
+        for _ in 0..context.before {
+            if start <= sent {
+                break;
+            }
+            start = line_start(buf, start - 1);
+        }
+        while start < line.start {
+            let end = line_end(buf, start);
+            if !send(&mut selected, LineKind::Context, start..end)? {
+                return Ok(count);
+            }
+            start = end;
+        }
+        sent = line.end;
+        if !send(&mut selected, LineKind::Match, line)? {
+            return Ok(count);
+        }
+        after = context.after;
+    }
+    while after > 0 && sent < buf.len() {
+        let end = line_end(buf, sent);
+        if !send(&mut selected, LineKind::Context, sent..end)? {
+            return Ok(count);
+        }
+        sent = end;
+        after -= 1;
+    }
+    Ok(count)
+}
+
+/// `send_lines` へ渡す、選ばれた行の範囲を先頭から順に返すもの
+trait SelectLines: Iterator<Item = Range<usize>> {
+    /// `line` の中で一致した部分の、行の先頭からの範囲。`None` なら出力するときに行ごとに検索する。
+    /// 行は先頭から順に問い合わせる
+    fn matches_in(&mut self, _line: &Range<usize>) -> Option<Vec<Range<usize>>> {
+        None
+    }
+}
+
+/// 前から順に問い合わせたオフセットの行番号を、前回の位置からの改行の数で求める
+#[derive(Debug)]
+struct LineNumbers {
+    offset: usize,
+    number: usize,
+}
+
+impl Default for LineNumbers {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            number: 1,
+        }
+    }
+}
+
+impl LineNumbers {
+    fn at(&mut self, buf: &[u8], offset: usize) -> usize {
+        self.number += memchr::memchr_iter(b'\n', &buf[self.offset..offset]).count();
+        self.offset = offset;
+        self.number
+    }
+}
+
+/// `buf` のうち、一致する行か `invert_match` なら一致しない行の範囲を先頭から順に返す
+struct SelectedLines<'a> {
+    buf: &'a [u8],
+    pattern: &'a Matcher,
+    invert_match: bool,
+    pos: usize,
+    /// 次に一致する行。探していなければ `None`、もうなければ `Some(None)`
+    next_match: Option<Option<Range<usize>>>,
+}
+
+impl Iterator for SelectedLines<'_> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        loop {
+            if self.pos >= self.buf.len() {
+                return None;
+            }
+            // 一致する行を過ぎるまでは探し直さない
+            let stale = match &self.next_match {
+                None => true,
+                Some(Some(line)) => line.start < self.pos,
+                Some(None) => false,
+            };
+            if stale {
+                self.next_match = Some(next_match_line(self.buf, self.pattern, self.pos));
+            }
+            let next_match = self.next_match.clone().flatten();
+            if !self.invert_match {
+                let line = next_match?;
+                self.pos = line.end;
+                return Some(line);
+            }
+            match next_match {
+                Some(line) if line.start == self.pos => self.pos = line.end,
+                _ => {
+                    let end = line_end(self.buf, self.pos);
+                    let line = self.pos..end;
+                    self.pos = end;
+                    return Some(line);
+                }
+            }
+        }
+    }
+}
+
+/// --multilineで、一致した部分がかかる行か `invert_match` ならかからない行の範囲を先頭から順に返す
+struct MultilineLines<'a> {
+    buf: &'a [u8],
+    matches: Peekable<Box<dyn Iterator<Item = Range<usize>> + 'a>>,
+    invert_match: bool,
+    pos: usize,
+    /// 一致した部分がかかる連続した行のうち、`pos` 以降にあるもの。もうなければ `None`
+    span: Option<Range<usize>>,
+    /// すでに読んだ一致のうち、まだ問い合わせていない行にかかるかもしれないもの
+    recent: VecDeque<Range<usize>>,
+}
+
+impl MultilineLines<'_> {
+    /// 次の一致がかかる行の範囲を、同じ行にかかる後の一致とまとめて返す
+    fn next_span(&mut self) -> Option<Range<usize>> {
+        let buf = self.buf;
+        // 空の一致は、その位置を含む行にかかるものとする
+        let last = |m: &Range<usize>| m.end.saturating_sub(1).max(m.start);
+        let m = self.matches.next().filter(|m| m.start < buf.len())?;
+        let mut span = line_start(buf, m.start)..line_end(buf, last(&m));
+        self.recent.push_back(m);
+        while let Some(m) = self.matches.next_if(|m| m.start < span.end) {
+            span.end = span.end.max(line_end(buf, last(&m)));
+            self.recent.push_back(m);
+        }
+        Some(span)
+    }
+}
+
+impl SelectLines for SelectedLines<'_> {}
+
+impl SelectLines for MultilineLines<'_> {
+    /// 一致した部分を行の範囲で切り取る。行末の改行だけにかかる部分は除く
+    fn matches_in(&mut self, line: &Range<usize>) -> Option<Vec<Range<usize>>> {
+        let text = &self.buf[line.clone()];
+        let end = line.start + text.strip_suffix(b"\n").unwrap_or(text).len();
+        while self.recent.front().is_some_and(|m| m.end <= line.start) {
+            self.recent.pop_front();
+        }
+        let matches = self
+            .recent
+            .iter()
+            .take_while(|m| m.start < end)
+            .map(|m| m.start.max(line.start) - line.start..m.end.min(end) - line.start)
+            .filter(|m| !m.is_empty())
+            .collect();
+        Some(matches)
+    }
+}
+
+impl Iterator for MultilineLines<'_> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        while self.pos < self.buf.len() {
+            if self.span.as_ref().map_or(true, |span| span.end <= self.pos) {
+                self.span = self.next_span();
+            }
+            let span = self.span.clone();
+            if !self.invert_match {
+                self.pos = self.pos.max(span.as_ref()?.start);
+            }
+            let end = line_end(self.buf, self.pos);
+            let line = self.pos..end;
+            self.pos = end;
+            let touched = span.is_some_and(|span| span.start <= line.start);
+            if touched != self.invert_match {
+                return Some(line);
+            }
+        }
+        None
+    }
+}
+
+/// `pos` 以降で最初に一致する行の範囲を返す。`pos` は行の先頭でなければならない。
+/// `buf` 全体での一致は改行をまたぐことがあるので、見つかった行だけでもう1度確かめる
+fn next_match_line(buf: &[u8], pattern: &Matcher, mut pos: usize) -> Option<Range<usize>> {
+    while pos < buf.len() {
+        let found = pos + pattern.find(&buf[pos..])?.start;
+        let start = line_start(buf, found);
+        if start >= buf.len() {
+            return None;
+        }
+        let end = line_end(buf, found);
+        let line = &buf[start..end];
+        if pattern.is_match(line.strip_suffix(b"\n").unwrap_or(line)) {
+            return Some(start..end);
+        }
+        pos = end;
+    }
+    None
+}
+
+/// `pos` を含む行の先頭
+fn line_start(buf: &[u8], pos: usize) -> usize {
+    memchr::memrchr(b'\n', &buf[..pos]).map_or(0, |i| i + 1)
+}
+
+/// `pos` を含む行の、改行を含めた終わり
+fn line_end(buf: &[u8], pos: usize) -> usize {
+    memchr::memchr(b'\n', &buf[pos..]).map_or(buf.len(), |i| pos + i + 1)
+}
+
+/// コマンドラインで指定されたパスのうち、検索するファイルと再帰的に辿るディレクトリを返す
+fn find_operands(paths: &[String], recursive: bool, walk: &WalkOptions) -> Vec<Result<String>> {
     let mut results = vec![];
     for path in paths {
         match path.as_str() {
             "-" => results.push(Ok(path.to_string())),
             _ => match fs::metadata(path) {
                 Ok(metadata) => {
+                    let name = Path::new(path).file_name();
                     if metadata.is_dir() {
-                        if recursive {
-                            WalkDir::new(path)
-                                .into_iter()
-                                .flatten()
-                                .filter(|entry| entry.file_type().is_file())
-                                .for_each(|entry| {
-                                    results.push(Ok(entry.path().display().to_string()))
-                                });
-                        } else {
+                        if !recursive {
                             results.push(Err(anyhow!("{path} is a directory")))
+                        } else if !walk.is_excluded_dir(name) {
+                            results.push(Ok(path.to_string()));
                         }
-                    } else if metadata.is_file() {
+                    } else if metadata.is_file() && walk.is_included_file(name) {
                         results.push(Ok(path.to_string()));
                     }
                 }
@@ -137,30 +1513,66 @@ fn find_files(paths: &[String], recursive: bool) -> Vec<Result<String>> {
     results
 }
 
+fn find_files(paths: &[String], recursive: bool, walk: &WalkOptions) -> Vec<Result<String>> {
+    let mut results = vec![];
+    for operand in find_operands(paths, recursive, walk) {
+        match operand {
+            Ok(path) if path != "-" && Path::new(&path).is_dir() => walk
+                .builder(&path)
+                .build()
+                .flatten()
+                .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+                .for_each(|entry| results.push(Ok(entry.path().display().to_string()))),
+            _ => results.push(operand),
+        }
+    }
+    results
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
+    use std::{
+        env, fs,
+        io::Cursor,
+        path::{Path, PathBuf},
+    };
 
-    use super::{find_files, find_lines};
+    use super::{
+        build_glob_set, build_overrides, find_files, find_lines, find_lines_in_buf, find_multiline,
+        Context, LineKind, Matcher, MatcherBuilder, WalkOptions,
+    };
+    use anyhow::anyhow;
     use rand::{distributions::Alphanumeric, Rng};
-    use regex::{Regex, RegexBuilder};
+    use regex::bytes::{Regex, RegexBuilder};
 
     #[test]
     fn test_find_files() {
         // 存在することがわかっているファイルを見つけられることを確認する
-        let files = find_files(&["./tests/inputs/fox.txt".to_string()], false);
+        let files = find_files(
+            &["./tests/inputs/fox.txt".to_string()],
+            false,
+            &WalkOptions::default(),
+        );
         assert_eq!(files.len(), 1);
         assert_eq!(files[0].as_ref().unwrap(), "./tests/inputs/fox.txt");
 
         // recursiveなしの場合、ディレクトリを拒否する
-        let files = find_files(&["./tests/inputs".to_string()], false);
+        let files = find_files(
+            &["./tests/inputs".to_string()],
+            false,
+            &WalkOptions::default(),
+        );
         assert_eq!(files.len(), 1);
         if let Err(e) = &files[0] {
             assert_eq!(e.to_string(), "./tests/inputs is a directory");
         }
 
         // ディレクトリ内の4つのファイルを再帰的に検索できることを確認する
-        let res = find_files(&["./tests/inputs".to_string()], true);
+        let res = find_files(
+            &["./tests/inputs".to_string()],
+            true,
+            &WalkOptions::default(),
+        );
         let mut files: Vec<String> = res
             .iter()
             .map(|r| r.as_ref().unwrap().replace('\\', "/"))
@@ -185,40 +1597,474 @@ mod tests {
             .collect();
 
         // エラーとして不正なファイルを返すことを確認する
-        let files = find_files(&[bad], false);
+        let files = find_files(&[bad], false, &WalkOptions::default());
         assert_eq!(files.len(), 1);
         assert!(files[0].is_err());
     }
 
+    /// 一時ディレクトリに `files` を作り、そのパスを返す
+    fn make_tree(files: &[(&str, &str)]) -> PathBuf {
+        let name: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(7)
+            .map(char::from)
+            .collect();
+        let root = env::temp_dir().join(format!("grepr-{name}"));
+        for (path, contents) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        root
+    }
+
+    /// `root` 以下で見つかったファイルを、`root` からの相対パスで並べて返す
+    fn walk_tree(root: &Path, walk: &WalkOptions) -> Vec<String> {
+        let mut files: Vec<String> = find_files(&[root.display().to_string()], true, walk)
+            .iter()
+            .map(|r| {
+                let path = PathBuf::from(r.as_ref().unwrap());
+                let path = path.strip_prefix(root).unwrap().display().to_string();
+                path.replace('\\', "/")
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn test_find_files_ignore() {
+        let root = make_tree(&[
+            (".git/HEAD", ""),
+            (".gitignore", "ignored.txt\ntarget/\n"),
+            (".grepignore", "custom.txt\n"),
+            (".hidden.txt", ""),
+            ("custom.txt", ""),
+            ("ignored.txt", ""),
+            ("kept.txt", ""),
+            ("target/debug.txt", ""),
+        ]);
+
+        // 既定では隠しファイルと無視するファイルを辿らない
+        let files = walk_tree(&root, &WalkOptions::default());
+        assert_eq!(files, vec!["kept.txt"]);
+
+        // --hidden
+        let walk = WalkOptions {
+            hidden: true,
+            ..Default::default()
+        };
+        let files = walk_tree(&root, &walk);
+        assert_eq!(
+            files,
+            vec![
+                ".git/HEAD",
+                ".gitignore",
+                ".grepignore",
+                ".hidden.txt",
+                "kept.txt"
+            ]
+        );
+
+        // --no-ignore-vcsでは.grepignoreだけを使う
+        let walk = WalkOptions {
+            no_ignore_vcs: true,
+            ..Default::default()
+        };
+        let files = walk_tree(&root, &walk);
+        assert_eq!(files, vec!["ignored.txt", "kept.txt", "target/debug.txt"]);
+
+        // --no-ignore
+        let walk = WalkOptions {
+            no_ignore: true,
+            ..Default::default()
+        };
+        let files = walk_tree(&root, &walk);
+        assert_eq!(
+            files,
+            vec!["custom.txt", "ignored.txt", "kept.txt", "target/debug.txt"]
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_find_files_globs() {
+        let root = make_tree(&[
+            ("a.rs", ""),
+            ("b.txt", ""),
+            ("node_modules/e.js", ""),
+            ("sub/c.rs", ""),
+            ("target/d.rs", ""),
+        ]);
+        let globs = |globs: &[&str]| {
+            let globs: Vec<_> = globs.iter().map(|glob| glob.to_string()).collect();
+            build_glob_set(&globs).unwrap()
+        };
+
+        // --include
+        let walk = WalkOptions {
+            include: globs(&["*.rs"]),
+            ..Default::default()
+        };
+        let files = walk_tree(&root, &walk);
+        assert_eq!(files, vec!["a.rs", "sub/c.rs", "target/d.rs"]);
+
+        // --includeと--exclude
+        let walk = WalkOptions {
+            include: globs(&["*.rs"]),
+            exclude: globs(&["c.*"]),
+            ..Default::default()
+        };
+        let files = walk_tree(&root, &walk);
+        assert_eq!(files, vec!["a.rs", "target/d.rs"]);
+
+        // --exclude-dir
+        let walk = WalkOptions {
+            exclude_dir: globs(&["target", "node_*"]),
+            ..Default::default()
+        };
+        let files = walk_tree(&root, &walk);
+        assert_eq!(files, vec!["a.rs", "b.txt", "sub/c.rs"]);
+
+        // -gは後に指定したものほど優先する
+        let overrides = |globs: &[&str]| {
+            let globs: Vec<_> = globs.iter().map(|glob| glob.to_string()).collect();
+            build_overrides(&globs).unwrap()
+        };
+        let walk = WalkOptions {
+            overrides: overrides(&["*.rs", "!target"]),
+            ..Default::default()
+        };
+        let files = walk_tree(&root, &walk);
+        assert_eq!(files, vec!["a.rs", "sub/c.rs"]);
+
+        let walk = WalkOptions {
+            overrides: overrides(&["!*.rs", "c.rs"]),
+            ..Default::default()
+        };
+        let files = walk_tree(&root, &walk);
+        assert_eq!(files, vec!["sub/c.rs"]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    fn collect_lines(text: &[u8], pattern: &Matcher, invert_match: bool) -> Vec<String> {
+        let mut lines = vec![];
+        let count = find_lines(
+            Cursor::new(text),
+            pattern,
+            invert_match,
+            Context::default(),
+            None,
+            |line| {
+                lines.push(String::from_utf8_lossy(line.text).into_owned());
+                Ok(true)
+            },
+        )
+        .unwrap();
+        assert_eq!(count, lines.len());
+        lines
+    }
+
     #[test]
     fn test_find_lines() {
         let text = b"Lorem\nIpsum\r\nDOLOR";
 
         // 「or」というパターンは「Lorem」という1行にマッチするはず
-        let re1 = Regex::new("or").unwrap();
-        let matches = find_lines(Cursor::new(&text), &re1, false);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 1);
+        let re1 = Matcher::from(Regex::new("or").unwrap());
+        let matches = collect_lines(text, &re1, false);
+        assert_eq!(matches, vec!["Lorem\n"]);
 
         // マッチを反転させた場合、残りの2行にマッチするはず
-        let matches = find_lines(Cursor::new(&text), &re1, true);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 2);
+        let matches = collect_lines(text, &re1, true);
+        assert_eq!(matches, vec!["Ipsum\r\n", "DOLOR"]);
 
         // 大文字と小文字を区別しない正規表現
-        let re2 = RegexBuilder::new("or")
-            .case_insensitive(true)
-            .build()
-            .unwrap();
+        let re2 = Matcher::from(
+            RegexBuilder::new("or")
+                .case_insensitive(true)
+                .build()
+                .unwrap(),
+        );
 
         // 「Lorem」と「DOLOR」の2行にマッチするはず
-        let matches = find_lines(Cursor::new(&text), &re2, false);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 2);
+        let matches = collect_lines(text, &re2, false);
+        assert_eq!(matches, vec!["Lorem\n", "DOLOR"]);
 
         // マッチを反転させた場合、残りの1行にマッチするはず
-        let matches = find_lines(Cursor::new(&text), &re2, true);
-        assert!(matches.is_ok());
-        assert_eq!(matches.unwrap().len(), 1);
+        let matches = collect_lines(text, &re2, true);
+        assert_eq!(matches, vec!["Ipsum\r\n"]);
+    }
+
+    #[test]
+    fn test_find_lines_streaming() {
+        let text = b"foo\nbar\nfoo\n";
+        let re = Matcher::from(Regex::new("foo").unwrap());
+
+        // 行を保持しなくても一致した行数を数えられることを確認する
+        let count = find_lines(
+            Cursor::new(text),
+            &re,
+            false,
+            Context::default(),
+            None,
+            |_| Ok(true),
+        )
+        .unwrap();
+        assert_eq!(count, 2);
+
+        // sinkのエラーで検索が打ち切られることを確認する
+        let mut seen = 0;
+        let res = find_lines(
+            Cursor::new(text),
+            &re,
+            false,
+            Context::default(),
+            None,
+            |_| {
+                seen += 1;
+                Err(anyhow!("stop"))
+            },
+        );
+        assert!(res.is_err());
+        assert_eq!(seen, 1);
+
+        // sinkがfalseを返すと以降の行を読まずに打ち切られることを確認する
+        let mut seen = 0;
+        let count = find_lines(
+            Cursor::new(text),
+            &re,
+            false,
+            Context::default(),
+            None,
+            |_| {
+                seen += 1;
+                Ok(false)
+            },
+        )
+        .unwrap();
+        assert_eq!(count, 1);
+        assert_eq!(seen, 1);
+    }
+
+    #[test]
+    fn test_find_lines_invalid_utf8() {
+        let text = b"caf\xe9\nfoo\xff bar\n";
+        let re = Matcher::from(Regex::new("bar").unwrap());
+
+        // 不正なUTF-8のバイトがあってもエラーにならず、行はそのまま渡される
+        let mut lines = vec![];
+        find_lines(
+            Cursor::new(text),
+            &re,
+            false,
+            Context::default(),
+            None,
+            |line| {
+                lines.push(line.text.to_vec());
+                Ok(true)
+            },
+        )
+        .unwrap();
+        assert_eq!(lines, vec![b"foo\xff bar\n".to_vec()]);
+    }
+
+    #[test]
+    fn test_find_lines_position() {
+        let text = b"Lorem\nIpsum\r\nDOLOR";
+        let re = Matcher::from(Regex::new("m|R$").unwrap());
+
+        // 行番号は1から、バイトオフセットは0から数える
+        let mut positions = vec![];
+        find_lines(
+            Cursor::new(text),
+            &re,
+            false,
+            Context::default(),
+            None,
+            |line| {
+                positions.push((line.number, line.offset));
+                Ok(true)
+            },
+        )
+        .unwrap();
+        assert_eq!(positions, vec![(1, 0), (2, 6), (3, 13)]);
+    }
+
+    #[test]
+    fn test_find_lines_context() {
+        let text = b"a\nb\nfoo\nc\nd\ne\nfoo\nf\nfoo\ng\n";
+        let re = Matcher::from(Regex::new("foo").unwrap());
+        let collect = |context| {
+            let mut lines = vec![];
+            find_lines(Cursor::new(text), &re, false, context, None, |line| {
+                lines.push((line.kind, line.number));
+                Ok(true)
+            })
+            .unwrap();
+            lines
+        };
+        use LineKind::{Context as C, Match as M};
+
+        // 前後1行ずつの文脈行が出力され、重なった範囲は1度だけ出力される
+        let lines = collect(Context {
+            before: 1,
+            after: 1,
+        });
+        assert_eq!(
+            lines,
+            vec![
+                (C, 2),
+                (M, 3),
+                (C, 4),
+                (C, 6),
+                (M, 7),
+                (C, 8),
+                (M, 9),
+                (C, 10)
+            ]
+        );
+
+        // 直前の行はリングバッファの長さだけ出力される
+        let lines = collect(Context {
+            before: 2,
+            after: 0,
+        });
+        assert_eq!(
+            lines,
+            vec![
+                (C, 1),
+                (C, 2),
+                (M, 3),
+                (C, 5),
+                (C, 6),
+                (M, 7),
+                (C, 8),
+                (M, 9)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_multiline() {
+        let text = b"fn foo(\n    arg: u32,\n) {}\nfn bar() {}\nfoo\n";
+        let collect = |pattern: &str, invert_match, context| {
+            let re = MatcherBuilder::new().build(&[pattern]).unwrap();
+            let mut lines = vec![];
+            let mut matches = vec![];
+            let count = find_multiline(text, &re, invert_match, context, None, |line| {
+                lines.push((line.kind, line.number, line.offset));
+                matches.push(line.matches.unwrap());
+                Ok(true)
+            })
+            .unwrap();
+            (count, lines, matches)
+        };
+        use LineKind::{Context as C, Match as M};
+
+        // 改行をまたぐ一致がかかるすべての行を、正しい行番号と文脈行とともに渡す
+        let context = Context {
+            before: 0,
+            after: 1,
+        };
+        let (count, lines, matches) = collect(r"fn foo\(\n\s*arg", false, context);
+        assert_eq!(count, 2);
+        assert_eq!(lines, vec![(M, 1, 0), (M, 2, 8), (C, 3, 22)]);
+        // 一致した部分は行ごとに切り取り、行末の改行は含めない
+        assert_eq!(matches, vec![vec![0..7], vec![0..7], vec![]]);
+
+        let (count, lines, _) = collect(r"fn foo\(\n\s*arg", true, Context::default());
+        assert_eq!(count, 3);
+        assert_eq!(lines, vec![(M, 3, 22), (M, 4, 27), (M, 5, 39)]);
+
+        // 改行で終わる一致は次の行にかからず、同じ行にかかる一致はまとめる
+        let (_, lines, matches) = collect(r",\n|fn|\(", false, Context::default());
+        assert_eq!(lines, vec![(M, 1, 0), (M, 2, 8), (M, 4, 27)]);
+        assert_eq!(
+            matches,
+            vec![vec![0..2, 6..7], vec![12..13], vec![0..2, 6..7]]
+        );
+
+        // 末尾の空の一致はどの行にもかからない
+        let (count, _, _) = collect("^", false, Context::default());
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn test_find_lines_max_count() {
+        let text = b"foo\na\nfoo\nb\nfoo\n";
+        let re = Matcher::from(Regex::new("foo").unwrap());
+        let mut lines = vec![];
+        let mut file = Cursor::new(text);
+        let context = Context {
+            before: 0,
+            after: 2,
+        };
+        let count = find_lines(&mut file, &re, false, context, Some(1), |line| {
+            lines.push((line.kind, line.number));
+            Ok(true)
+        })
+        .unwrap();
+        use LineKind::{Context as C, Match as M};
+
+        // 上限に達した後に一致した行は文脈行として渡し、文脈行を渡し終えたら読み込みを打ち切る
+        assert_eq!(count, 1);
+        assert_eq!(lines, vec![(M, 1), (C, 2), (C, 3)]);
+        assert_eq!(file.position(), 10);
+    }
+
+    #[test]
+    fn test_find_lines_in_buf() {
+        let texts: [&[u8]; 4] = [
+            b"a\nb\nfoo\nc\nd\ne\nfoo\nf\nfoo\ng\n",
+            b"foo bar\n\nbar\nfoo",
+            b"\n\nfoo\n\n",
+            b"",
+        ];
+        let patterns = ["foo", "^$", "o\\s+b", "^b|r$", ""];
+        let contexts = [(0, 0), (1, 1), (2, 0), (0, 3)];
+        let max_counts = [None, Some(0), Some(1), Some(2)];
+        let collect = |lines: &mut Vec<_>, line: super::Line| {
+            lines.push((line.kind, line.number, line.offset, line.text.to_vec()));
+            Ok(true)
+        };
+
+        // ファイル全体をまとめて検索しても、1行ずつ読んだときと同じ行が渡される
+        for text in texts {
+            for pattern in patterns {
+                let re = MatcherBuilder::new().build(&[pattern]).unwrap();
+                for invert_match in [false, true] {
+                    for ((before, after), max_count) in contexts.iter().flat_map(|&context| {
+                        max_counts
+                            .iter()
+                            .map(move |&max_count| (context, max_count))
+                    }) {
+                        let context = Context { before, after };
+                        let mut expected = vec![];
+                        let count = find_lines(
+                            Cursor::new(text),
+                            &re,
+                            invert_match,
+                            context,
+                            max_count,
+                            |l| collect(&mut expected, l),
+                        )
+                        .unwrap();
+                        let mut lines = vec![];
+                        let count_in_buf =
+                            find_lines_in_buf(text, &re, invert_match, context, max_count, |l| {
+                                collect(&mut lines, l)
+                            })
+                            .unwrap();
+                        assert_eq!(
+                            lines, expected,
+                            "{pattern:?} {invert_match} {context:?} {max_count:?}"
+                        );
+                        assert_eq!(count_in_buf, count);
+                    }
+                }
+            }
+        }
     }
 }
@@ -1,6 +1,9 @@
 fn main() {
-    if let Err(err) = grepr::get_args().and_then(grepr::run) {
-        eprintln!("{err}");
-        std::process::exit(1);
+    match grepr::get_args().and_then(grepr::run) {
+        Ok(code) => std::process::exit(code),
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
     }
 }
@@ -0,0 +1,383 @@
+use aho_corasick::{AhoCorasick, MatchKind};
+use anyhow::Result;
+use regex::bytes::{Captures, Regex, RegexBuilder};
+use std::ops::Range;
+
+/// 行の検索に使う正規表現、または固定文字列の集合
+#[derive(Debug)]
+pub enum Matcher {
+    Regex(Regex),
+    Literal(AhoCorasick),
+}
+
+impl From<Regex> for Matcher {
+    fn from(regex: Regex) -> Self {
+        Self::Regex(regex)
+    }
+}
+
+/// `Matcher` を作る。`RegexBuilder` と同じく設定を積み上げてから `build` する
+#[derive(Debug, Default, Clone)]
+pub struct MatcherBuilder {
+    case_insensitive: bool,
+    fixed_strings: bool,
+    word: bool,
+    line: bool,
+    no_unicode: bool,
+    dot_matches_new_line: bool,
+}
+
+impl MatcherBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn case_insensitive(&mut self, yes: bool) -> &mut Self {
+        self.case_insensitive = yes;
+        self
+    }
+
+    /// パターンを正規表現ではなく固定文字列として扱う
+    pub fn fixed_strings(&mut self, yes: bool) -> &mut Self {
+        self.fixed_strings = yes;
+        self
+    }
+
+    /// 前後が単語構成文字でない部分にだけ一致させる
+    pub fn word(&mut self, yes: bool) -> &mut Self {
+        self.word = yes;
+        self
+    }
+
+    /// 行全体にだけ一致させる。`word` より優先する
+    pub fn line(&mut self, yes: bool) -> &mut Self {
+        self.line = yes;
+        self
+    }
+
+    /// Unicodeを無効にし、`.` や `\xFF` を1バイトに一致させる
+    pub fn no_unicode(&mut self, yes: bool) -> &mut Self {
+        self.no_unicode = yes;
+        self
+    }
+
+    /// `.` を改行にも一致させる。ファイル全体をまとめて検索するときだけ意味がある
+    pub fn dot_matches_new_line(&mut self, yes: bool) -> &mut Self {
+        self.dot_matches_new_line = yes;
+        self
+    }
+
+    /// いずれかのパターンに一致する `Matcher` を作る。パターンがなければ何にも一致しない
+    pub fn build(&self, patterns: &[&str]) -> Result<Matcher> {
+        if !self.fixed_strings {
+            return self.build_regex(patterns);
+        }
+        // Aho-Corasickは単語や行の境界を扱えず、大文字小文字の無視もASCIIに限られるため、
+        // それらが必要なときはエスケープした正規表現を使う
+        if self.word
+            || self.line
+            || (self.case_insensitive && !patterns.iter().all(|pattern| pattern.is_ascii()))
+        {
+            let escaped: Vec<_> = patterns
+                .iter()
+                .map(|pattern| regex::escape(pattern))
+                .collect();
+            let escaped: Vec<_> = escaped.iter().map(String::as_str).collect();
+            return self.build_regex(&escaped);
+        }
+        Ok(Matcher::Literal(
+            AhoCorasick::builder()
+                .match_kind(MatchKind::LeftmostLongest)
+                .ascii_case_insensitive(self.case_insensitive)
+                .build(patterns)?,
+        ))
+    }
+
+    fn build_regex(&self, patterns: &[&str]) -> Result<Matcher> {
+        if patterns.is_empty() {
+            return Ok(Matcher::Literal(AhoCorasick::new(patterns)?));
+        }
+        let alternation = match patterns {
+            [pattern] => pattern.to_string(),
+            _ => patterns
+                .iter()
+                .map(|pattern| format!("(?:{pattern})"))
+                .collect::<Vec<_>>()
+                .join("|"),
+        };
+        // grepの-wと同じく、一致の直前と直後だけを調べる半分の単語境界を使う。
+        // `\b` と違い、`@foo` のように単語構成文字以外で始まるパターンも正しく扱える
+        let pattern = if self.line {
+            format!("^(?:{alternation})$")
+        } else if self.word {
+            format!(r"\b{{start-half}}(?:{alternation})\b{{end-half}}")
+        } else {
+            alternation
+        };
+        // ファイル全体をまとめて検索するときも `^` と `$` が各行の先頭と末尾に一致するようにする。
+        // 1行だけを検索するときは改行を含まないので、結果は変わらない
+        Ok(Matcher::Regex(
+            RegexBuilder::new(&pattern)
+                .multi_line(true)
+                .case_insensitive(self.case_insensitive)
+                .unicode(!self.no_unicode)
+                .dot_matches_new_line(self.dot_matches_new_line)
+                .build()?,
+        ))
+    }
+}
+
+impl Matcher {
+    pub fn is_match(&self, haystack: &[u8]) -> bool {
+        match self {
+            Self::Regex(regex) => regex.is_match(haystack),
+            Self::Literal(ac) => ac.is_match(haystack),
+        }
+    }
+
+    pub fn find(&self, haystack: &[u8]) -> Option<Range<usize>> {
+        self.find_iter(haystack).next()
+    }
+
+    /// 重ならない一致の範囲を先頭から順に返す
+    pub fn find_iter<'a>(
+        &'a self,
+        haystack: &'a [u8],
+    ) -> Box<dyn Iterator<Item = Range<usize>> + 'a> {
+        match self {
+            Self::Regex(regex) => Box::new(regex.find_iter(haystack).map(|m| m.range())),
+            Self::Literal(ac) => Box::new(ac.find_iter(haystack).map(|m| m.range())),
+        }
+    }
+
+    /// 一致した部分をすべて、`template` の `$1`、`${name}`、`$$` を展開して `wrap` に通したものに置き換える
+    pub fn replace_all<F>(&self, haystack: &[u8], template: &[u8], mut wrap: F) -> Vec<u8>
+    where
+        F: FnMut(Vec<u8>) -> Vec<u8>,
+    {
+        match self {
+            Self::Regex(regex) => regex
+                .replace_all(haystack, |caps: &Captures| {
+                    let mut dst = vec![];
+                    caps.expand(template, &mut dst);
+                    wrap(dst)
+                })
+                .into_owned(),
+            Self::Literal(_) => {
+                let mut replaced = vec![];
+                let mut last = 0;
+                for (m, replacement) in self.expand_iter(haystack, template) {
+                    replaced.extend_from_slice(&haystack[last..m.start]);
+                    replaced.extend(wrap(replacement));
+                    last = m.end;
+                }
+                replaced.extend_from_slice(&haystack[last..]);
+                replaced
+            }
+        }
+    }
+
+    /// 一致した範囲と、その一致で `template` を展開したものを先頭から順に返す
+    pub fn expand_iter(&self, haystack: &[u8], template: &[u8]) -> Vec<(Range<usize>, Vec<u8>)> {
+        match self {
+            Self::Regex(regex) => regex
+                .captures_iter(haystack)
+                .map(|caps| {
+                    let mut dst = vec![];
+                    caps.expand(template, &mut dst);
+                    (caps.get(0).unwrap().range(), dst)
+                })
+                .collect(),
+            Self::Literal(ac) => ac
+                .find_iter(haystack)
+                .map(|m| {
+                    let mut dst = vec![];
+                    expand_literal(template, &haystack[m.range()], &mut dst);
+                    (m.range(), dst)
+                })
+                .collect(),
+        }
+    }
+}
+
+/// `Captures::expand` と同じ規則で、グループが一致全体しかない固定文字列の `template` を展開する。
+/// `$0` は一致した部分に、`$$` は `$` になり、存在しないグループは空になる
+fn expand_literal(template: &[u8], matched: &[u8], dst: &mut Vec<u8>) {
+    let is_name = |b: &u8| b.is_ascii_alphanumeric() || *b == b'_';
+    let mut rest = template;
+    while let Some(i) = rest.iter().position(|&b| b == b'$') {
+        dst.extend_from_slice(&rest[..i]);
+        rest = &rest[i + 1..];
+        let (name, len) = if rest.first() == Some(&b'$') {
+            dst.push(b'$');
+            rest = &rest[1..];
+            continue;
+        } else if rest.first() == Some(&b'{') {
+            match rest.iter().position(|&b| b == b'}') {
+                Some(end) if end > 1 => (&rest[1..end], end + 1),
+                _ => (&rest[..0], 0),
+            }
+        } else {
+            let len = rest.iter().take_while(|b| is_name(b)).count();
+            (&rest[..len], len)
+        };
+        if len == 0 {
+            dst.push(b'$');
+            continue;
+        }
+        if name.iter().all(|&b| b == b'0') {
+            dst.extend_from_slice(matched);
+        }
+        rest = &rest[len..];
+    }
+    dst.extend_from_slice(rest);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Matcher, MatcherBuilder};
+
+    #[test]
+    fn test_fixed_strings() {
+        // 正規表現のメタ文字をそのまま探す
+        let matcher = MatcherBuilder::new()
+            .fixed_strings(true)
+            .build(&["a.b[0]"])
+            .unwrap();
+        assert!(matches!(matcher, Matcher::Literal(_)));
+        assert!(matcher.is_match(b"x = a.b[0];"));
+        assert!(!matcher.is_match(b"x = axb0;"));
+
+        // 複数の文字列のうち最も長いものに一致する
+        let matcher = MatcherBuilder::new()
+            .fixed_strings(true)
+            .case_insensitive(true)
+            .build(&["foo", "foobar", "baz"])
+            .unwrap();
+        let text = b"FOOBAR baz foo";
+        let found: Vec<_> = matcher.find_iter(text).map(|m| &text[m]).collect();
+        assert_eq!(found, vec![&b"FOOBAR"[..], b"baz", b"foo"]);
+
+        // 非ASCIIの文字列は正規表現で大文字小文字を無視する
+        let matcher = MatcherBuilder::new()
+            .fixed_strings(true)
+            .case_insensitive(true)
+            .build(&["straße."])
+            .unwrap();
+        assert!(matches!(matcher, Matcher::Regex(_)));
+        assert!(matcher.is_match("STRASSE. STRAẞE.".as_bytes()));
+        assert!(!matcher.is_match("STRAẞEN".as_bytes()));
+    }
+
+    #[test]
+    fn test_regex() {
+        // いずれかの正規表現に一致する
+        let matcher = MatcherBuilder::new().build(&["^fo+$", "ba[rz]"]).unwrap();
+        assert!(matcher.is_match(b"foo"));
+        assert!(matcher.is_match(b"a bar"));
+        assert!(!matcher.is_match(b"a foo"));
+
+        // パターンがなければ何にも一致しない
+        let matcher = MatcherBuilder::new().build(&[]).unwrap();
+        assert!(!matcher.is_match(b""));
+        assert!(!matcher.is_match(b"foo"));
+    }
+
+    #[test]
+    fn test_word() {
+        let matcher = MatcherBuilder::new().word(true).build(&["foo"]).unwrap();
+        assert!(matcher.is_match(b"foo"));
+        assert!(matcher.is_match(b"(foo)"));
+        assert!(!matcher.is_match(b"foobar"));
+        assert!(!matcher.is_match(b"foo_"));
+
+        // 非ASCIIの文字も単語構成文字として扱う
+        assert!(!matcher.is_match("éfoo".as_bytes()));
+        assert_eq!(matcher.find("fooé foo".as_bytes()), Some(6..9));
+
+        // 単語構成文字以外で始まるパターンは、直前が単語構成文字なら一致しない
+        let matcher = MatcherBuilder::new().word(true).build(&["@foo"]).unwrap();
+        assert!(matcher.is_match(b"@foo"));
+        assert!(matcher.is_match(b"a @foo"));
+        assert!(!matcher.is_match(b"a@foo"));
+
+        // 空のパターンは単語構成文字に挟まれていない位置に一致する
+        let matcher = MatcherBuilder::new().word(true).build(&[""]).unwrap();
+        assert!(matcher.is_match(b""));
+        assert!(matcher.is_match(b"x  y"));
+        assert!(!matcher.is_match(b"a b"));
+
+        // 固定文字列でも単語単位で一致する
+        let matcher = MatcherBuilder::new()
+            .fixed_strings(true)
+            .word(true)
+            .build(&["a.b"])
+            .unwrap();
+        assert!(matcher.is_match(b"x a.b y"));
+        assert!(!matcher.is_match(b"a.bc"));
+    }
+
+    #[test]
+    fn test_line() {
+        let matcher = MatcherBuilder::new()
+            .line(true)
+            .build(&["foo", "ba."])
+            .unwrap();
+        assert!(matcher.is_match(b"foo"));
+        assert!(matcher.is_match(b"bar"));
+        assert!(!matcher.is_match(b"foo "));
+        assert!(!matcher.is_match(b"foobar"));
+
+        // 行全体の一致は単語単位の一致より優先する
+        let matcher = MatcherBuilder::new()
+            .word(true)
+            .line(true)
+            .fixed_strings(true)
+            .build(&["a b"])
+            .unwrap();
+        assert!(matcher.is_match(b"a b"));
+        assert!(!matcher.is_match(b"a b c"));
+    }
+
+    #[test]
+    fn test_no_unicode() {
+        // 既定では不正なUTF-8のバイトに一致しない
+        let matcher = MatcherBuilder::new().build(&[r"\xFF"]).unwrap();
+        assert!(!matcher.is_match(b"caf\xff"));
+        assert!(matcher.is_match("ÿ".as_bytes()));
+
+        // Unicodeを無効にすると1バイトに一致する
+        let matcher = MatcherBuilder::new()
+            .no_unicode(true)
+            .build(&[r"\xFF", r"^.$"])
+            .unwrap();
+        assert!(matcher.is_match(b"caf\xff"));
+        assert!(matcher.is_match(b"\xe9"));
+        assert!(!matcher.is_match("é".as_bytes()));
+    }
+
+    #[test]
+    fn test_replace_all() {
+        let matcher = MatcherBuilder::new()
+            .build(&[r"(?P<key>\w+)=(\w+)"])
+            .unwrap();
+        let replaced = matcher.replace_all(b"a=1 b=2", b"$2:${key} $$", |r| r);
+        assert_eq!(replaced, b"1:a $ 2:b $");
+
+        // 置き換えた部分だけに `wrap` が適用される
+        let replaced = matcher.replace_all(b"x a=1", b"$1", |r| [b"[", &r[..], b"]"].concat());
+        assert_eq!(replaced, b"x [a]");
+
+        let expanded = matcher.expand_iter(b"a=1 b=2", b"$2");
+        assert_eq!(expanded, vec![(0..3, b"1".to_vec()), (4..7, b"2".to_vec())]);
+
+        // 固定文字列は一致全体だけをグループとして展開する
+        let matcher = MatcherBuilder::new()
+            .fixed_strings(true)
+            .build(&["a.b"])
+            .unwrap();
+        assert!(matches!(matcher, Matcher::Literal(_)));
+        let replaced = matcher.replace_all(b"x a.b y", b"<$0|${0}|$1|$$|$>|${}", |r| r);
+        assert_eq!(replaced, b"x <a.b|a.b||$|$>|${} y");
+    }
+}
@@ -0,0 +1,348 @@
+use crate::{Config, Line, LineKind};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::{json, Value};
+use std::{
+    io::{self, Write},
+    ops::Range,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// 1つのファイルの検索結果を出力する
+pub trait Printer {
+    /// ファイルを検索する前に呼ばれる
+    fn begin(&mut self, out: &mut dyn Write) -> io::Result<()>;
+
+    /// 一致した行と文脈行ごとに呼ばれる
+    fn line(&mut self, out: &mut dyn Write, line: &Line) -> io::Result<()>;
+
+    /// ファイルを検索した後に、一致した行数とバイナリファイルだったかを渡して呼ばれる
+    fn end(&mut self, out: &mut dyn Write, count: usize, binary: bool) -> io::Result<()>;
+}
+
+/// 設定に合った `Printer` を作る。
+/// `separate` が真なら、文脈行を出力するときに最初の行の前にも区切りを書く
+pub fn new<'a>(
+    config: &'a Config,
+    filename: &'a str,
+    with_filename: bool,
+    separate: bool,
+) -> Box<dyn Printer + 'a> {
+    if config.json {
+        Box::new(JsonPrinter {
+            config,
+            filename,
+            matches: 0,
+        })
+    } else {
+        Box::new(StandardPrinter {
+            config,
+            filename,
+            with_filename,
+            separate,
+            last_number: None,
+        })
+    }
+}
+
+/// grepと同じ形式で出力する
+struct StandardPrinter<'a> {
+    config: &'a Config,
+    filename: &'a str,
+    with_filename: bool,
+    separate: bool,
+    /// 最後に出力した行の行番号
+    last_number: Option<usize>,
+}
+
+impl StandardPrinter<'_> {
+    fn prefix(&self, sep: &str, number: usize, column: usize, offset: u64) -> String {
+        let config = self.config;
+        let colors = &config.colors;
+        let sep = colors.paint(&colors.separator, sep);
+        let mut prefix = String::new();
+        if self.with_filename {
+            prefix += &format!("{}{sep}", colors.paint(&colors.filename, self.filename));
+        }
+        if config.line_number {
+            let number = number.to_string();
+            prefix += &format!("{}{sep}", colors.paint(&colors.line_number, &number));
+        }
+        if config.column {
+            let column = column.to_string();
+            prefix += &format!("{}{sep}", colors.paint(&colors.line_number, &column));
+        }
+        if config.byte_offset {
+            let offset = offset.to_string();
+            prefix += &format!("{}{sep}", colors.paint(&colors.byte_offset, &offset));
+        }
+        prefix
+    }
+
+    /// 一致した部分を `match_sgr` で、それ以外の部分を `line_sgr` で着色する。
+    /// grepと同じく、一致した部分の手前ごとに `line_sgr` を始め、その上に `match_sgr` を重ねる。
+    /// 行のバイト列は変換せずにそのまま出力する
+    fn highlight(&self, line: &Line, text: &[u8], match_sgr: &str, line_sgr: &str) -> Vec<u8> {
+        let colors = &self.config.colors;
+        let mut highlighted = vec![];
+        let mut last = 0;
+        if !match_sgr.is_empty() {
+            for m in find_matches(self.config, line, text)
+                .into_iter()
+                .filter(|m| !m.is_empty())
+            {
+                highlighted.extend(colors.start(line_sgr).as_bytes());
+                highlighted.extend(&text[last..m.start]);
+                highlighted.extend(colors.paint_bytes(match_sgr, &text[m.clone()]));
+                last = m.end;
+            }
+        }
+        // CRLFの行末の `\r` は着色しない
+        let tail = &text[last..];
+        let (tail, cr) = match tail.strip_suffix(b"\r") {
+            Some(tail) => (tail, &b"\r"[..]),
+            None => (tail, &b""[..]),
+        };
+        highlighted.extend(colors.paint_bytes(line_sgr, tail));
+        highlighted.extend(cr);
+        highlighted
+    }
+}
+
+impl Printer for StandardPrinter<'_> {
+    fn begin(&mut self, _out: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn line(&mut self, out: &mut dyn Write, line: &Line) -> io::Result<()> {
+        let config = self.config;
+        let colors = &config.colors;
+        let text = line.text.strip_suffix(b"\n").unwrap_or(line.text);
+        if config.has_context() {
+            let gap = self
+                .last_number
+                .map_or(self.separate, |number| number + 1 != line.number);
+            if gap {
+                writeln!(out, "{}", colors.paint(&colors.separator, "--"))?;
+            }
+            self.last_number = Some(line.number);
+        }
+        // -oでは一致する行の一致した部分だけを出力する。-vなら文脈行が一致する行になる。
+        // 出力しない行も、区切りを出力するため行番号は記録する
+        if config.only_matching {
+            if (line.kind == LineKind::Match) == config.invert_match {
+                return Ok(());
+            }
+            let (sep, sgr) = match line.kind {
+                LineKind::Match => (":", &colors.selected_match),
+                LineKind::Context => ("-", &colors.context_match),
+            };
+            let matches = match &config.replace {
+                Some(template) => config.pattern.expand_iter(text, template),
+                None => config
+                    .pattern
+                    .find_iter(text)
+                    .map(|m| (m.clone(), text[m].to_vec()))
+                    .collect(),
+            };
+            for (m, text) in matches.into_iter().filter(|(m, _)| !m.is_empty()) {
+                let offset = line.offset + m.start as u64;
+                let prefix = self.prefix(sep, line.number, m.start + 1, offset);
+                out.write_all(prefix.as_bytes())?;
+                out.write_all(&colors.paint_bytes(sgr, &text))?;
+                out.write_all(b"\n")?;
+            }
+            return Ok(());
+        }
+        let (sep, column, text) = match line.kind {
+            LineKind::Match => (
+                ":",
+                find_matches(config, line, text)
+                    .first()
+                    .map_or(1, |m| m.start + 1),
+                if config.invert_match {
+                    self.highlight(line, text, "", &colors.selected_line)
+                } else if let Some(template) = &config.replace {
+                    config.pattern.replace_all(text, template, |replacement| {
+                        colors.paint_bytes(&colors.selected_match, &replacement)
+                    })
+                } else {
+                    self.highlight(line, text, &colors.selected_match, &colors.selected_line)
+                },
+            ),
+            LineKind::Context => (
+                "-",
+                1,
+                if config.invert_match {
+                    self.highlight(line, text, &colors.context_match, &colors.context_line)
+                } else {
+                    self.highlight(line, text, "", &colors.context_line)
+                },
+            ),
+        };
+        let prefix = self.prefix(sep, line.number, column, line.offset);
+        let newline: &[u8] = if line.text.ends_with(b"\n") {
+            b"\n"
+        } else {
+            b""
+        };
+        out.write_all(prefix.as_bytes())?;
+        out.write_all(&text)?;
+        out.write_all(newline)
+    }
+
+    fn end(&mut self, out: &mut dyn Write, count: usize, binary: bool) -> io::Result<()> {
+        let config = self.config;
+        let colors = &config.colors;
+        if config.quiet {
+            Ok(())
+        } else if config.files_with_matches || config.files_without_match {
+            if (count > 0) == config.files_with_matches {
+                writeln!(out, "{}", colors.paint(&colors.filename, self.filename))?;
+            }
+            Ok(())
+        } else if config.count {
+            if self.with_filename {
+                let filename = colors.paint(&colors.filename, self.filename);
+                write!(out, "{filename}{}", colors.paint(&colors.separator, ":"))?;
+            }
+            writeln!(out, "{count}")
+        } else if binary && count > 0 {
+            writeln!(out, "Binary file {} matches", self.filename)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// 1行に1つのJSONオブジェクトでイベントを出力する
+struct JsonPrinter<'a> {
+    config: &'a Config,
+    filename: &'a str,
+    /// これまでに出力した一致した部分の数
+    matches: usize,
+}
+
+impl Printer for JsonPrinter<'_> {
+    fn begin(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        write_event(
+            out,
+            "begin",
+            json!({ "path": data(self.filename.as_bytes()) }),
+        )
+    }
+
+    fn line(&mut self, out: &mut dyn Write, line: &Line) -> io::Result<()> {
+        let text = line.text.strip_suffix(b"\n").unwrap_or(line.text);
+        // 通常の出力で強調するのと同じく、-vなら文脈行の一致した部分を返す
+        let submatches: Vec<Range<usize>> =
+            if (line.kind == LineKind::Match) != self.config.invert_match {
+                find_matches(self.config, line, text)
+                    .into_iter()
+                    .filter(|m| !m.is_empty())
+                    .collect()
+            } else {
+                vec![]
+            };
+        if line.kind == LineKind::Match {
+            self.matches += submatches.len();
+        }
+        let kind = match line.kind {
+            LineKind::Match => "match",
+            LineKind::Context => "context",
+        };
+        let submatches: Vec<_> = submatches
+            .into_iter()
+            .map(|m| json!({ "match": data(&text[m.clone()]), "start": m.start, "end": m.end }))
+            .collect();
+        write_event(
+            out,
+            kind,
+            json!({
+                "path": data(self.filename.as_bytes()),
+                "lines": data(line.text),
+                "line_number": line.number,
+                "absolute_offset": line.offset,
+                "submatches": submatches,
+            }),
+        )
+    }
+
+    fn end(&mut self, out: &mut dyn Write, count: usize, binary: bool) -> io::Result<()> {
+        write_event(
+            out,
+            "end",
+            json!({
+                "path": data(self.filename.as_bytes()),
+                "binary": binary,
+                "stats": { "matched_lines": count, "matches": self.matches },
+            }),
+        )
+    }
+}
+
+/// すべてのファイルの検索結果の集計。--jsonでは最後に `summary` として出力する
+#[derive(Debug, Default)]
+pub struct Summary {
+    searches: AtomicUsize,
+    searches_with_match: AtomicUsize,
+    matched_lines: AtomicUsize,
+}
+
+impl Summary {
+    /// 1つのファイルを検索して一致した行数を加える
+    pub fn add(&self, count: usize) {
+        self.searches.fetch_add(1, Ordering::Relaxed);
+        if count > 0 {
+            self.searches_with_match.fetch_add(1, Ordering::Relaxed);
+        }
+        self.matched_lines.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_event(
+            out,
+            "summary",
+            json!({
+                "stats": {
+                    "searches": self.searches.load(Ordering::Relaxed),
+                    "searches_with_match": self.searches_with_match.load(Ordering::Relaxed),
+                    "matched_lines": self.matched_lines.load(Ordering::Relaxed),
+                },
+            }),
+        )
+    }
+}
+
+/// 行の中で一致した部分。--multilineでは行をまたぐ一致のうち、この行にかかる部分を返す
+fn find_matches(config: &Config, line: &Line, text: &[u8]) -> Vec<Range<usize>> {
+    match &line.matches {
+        Some(matches) => matches.clone(),
+        None => config.pattern.find_iter(text).collect(),
+    }
+}
+
+/// 読みやすいよう、`type` を先頭に書く
+fn write_event(out: &mut dyn Write, kind: &str, data: Value) -> io::Result<()> {
+    writeln!(out, r#"{{"type":"{kind}","data":{data}}}"#)
+}
+
+/// UTF-8として読めれば `{"text": ...}`、読めなければBase64で `{"bytes": ...}` とする
+fn data(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => json!({ "text": text }),
+        Err(_) => json!({ "bytes": BASE64.encode(bytes) }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::data;
+    use serde_json::json;
+
+    #[test]
+    fn test_data() {
+        assert_eq!(data(b"foo\n"), json!({ "text": "foo\n" }));
+        // UTF-8でなければBase64にする
+        assert_eq!(data(b"caf\xe9"), json!({ "bytes": "Y2Fm6Q==" }));
+    }
+}
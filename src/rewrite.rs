@@ -0,0 +1,212 @@
+use crate::{matcher::Matcher, BinaryFiles, Config};
+use anyhow::{anyhow, bail, Result};
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE};
+use similar::TextDiff;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    path::Path,
+    process,
+};
+
+/// --writeでファイルをどう書き換えるか
+#[derive(Debug)]
+pub struct WriteOptions {
+    /// 元のファイルを `FILE` + `backup` として残す
+    pub backup: Option<String>,
+    /// 書き換えずに変更をunified diffで出力する
+    pub dry_run: bool,
+}
+
+/// 1つのファイルの一致した部分を `template` で置き換え、一致した行数を返す。
+/// --dry-runなら書き換えずに、変更をunified diffで `out` に書き込む
+pub fn rewrite_file(
+    config: &Config,
+    filename: &str,
+    template: &[u8],
+    options: &WriteOptions,
+    out: &mut dyn Write,
+) -> Result<usize> {
+    let raw = match filename {
+        "-" if !options.dry_run => bail!("Cannot rewrite standard input"),
+        "-" => {
+            let mut buf = vec![];
+            io::stdin().read_to_end(&mut buf)?;
+            buf
+        }
+        _ => fs::read(filename)?,
+    };
+    // 検索と同じく、BOMの付いたファイルはUTF-8に変換して置き換え、書き込むときに元のエンコーディングに戻す
+    let (encoding, old) = match Encoding::for_bom(&raw) {
+        Some((encoding, bom_len)) => {
+            let (text, malformed) = encoding.decode_without_bom_handling(&raw[bom_len..]);
+            if malformed {
+                bail!("Invalid {} text; not rewritten", encoding.name());
+            }
+            (Some(encoding), text.into_owned().into_bytes())
+        }
+        None => (None, raw),
+    };
+    let (new, count) = replace_lines(&config.pattern, &old, template, config.max_count);
+    // 検索と同じく、最初のブロックにNULがあるバイナリファイルは-aがなければ書き換えない
+    if config.binary_files != BinaryFiles::Text && old[..old.len().min(8 * 1024)].contains(&0) {
+        if config.binary_files == BinaryFiles::WithoutMatch {
+            return Ok(0);
+        }
+        if count > 0 && !config.no_messages {
+            eprintln!("{filename}: Binary file matches; not rewritten (use -a to rewrite)");
+        }
+        return Ok(count);
+    }
+    if new == old {
+        return Ok(count);
+    }
+    if options.dry_run {
+        TextDiff::configure()
+            .diff_lines(&old, &new)
+            .unified_diff()
+            .header(filename, filename)
+            .to_writer(out)?;
+    } else {
+        let new = match encoding {
+            Some(encoding) => encode_with_bom(encoding, new)?,
+            None => new,
+        };
+        write_atomically(Path::new(filename), &new, options.backup.as_deref())?;
+    }
+    Ok(count)
+}
+
+/// UTF-8に変換して置き換えた `text` を、BOMを付けて `encoding` に戻す
+fn encode_with_bom(encoding: &'static Encoding, text: Vec<u8>) -> Result<Vec<u8>> {
+    let text = String::from_utf8(text)
+        .map_err(|_| anyhow!("Replaced text is not valid UTF-8; not rewritten"))?;
+    let mut bytes = vec![];
+    if encoding == UTF_16LE {
+        bytes.extend(b"\xff\xfe");
+        bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+    } else if encoding == UTF_16BE {
+        bytes.extend(b"\xfe\xff");
+        bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+    } else {
+        bytes.extend(b"\xef\xbb\xbf");
+        bytes.extend(text.into_bytes());
+    }
+    Ok(bytes)
+}
+
+/// 一致した行の一致した部分を `template` で置き換えた内容と、一致した行数を返す。
+/// 検索と同じく、行末の改行は一致の対象に含めず、-mの `max_count` 行より後は置き換えない
+fn replace_lines(
+    pattern: &Matcher,
+    text: &[u8],
+    template: &[u8],
+    max_count: Option<usize>,
+) -> (Vec<u8>, usize) {
+    let mut replaced = Vec::with_capacity(text.len());
+    let mut count = 0;
+    for line in text.split_inclusive(|&b| b == b'\n') {
+        let (line, newline) = match line.strip_suffix(b"\n") {
+            Some(line) => (line, &b"\n"[..]),
+            None => (line, &b""[..]),
+        };
+        if count < max_count.unwrap_or(usize::MAX) && pattern.is_match(line) {
+            count += 1;
+            replaced.extend(pattern.replace_all(line, template, |replacement| replacement));
+        } else {
+            replaced.extend(line);
+        }
+        replaced.extend(newline);
+    }
+    (replaced, count)
+}
+
+/// 同じディレクトリの一時ファイルに書き込んでから名前を変えて置き換え、
+/// 途中で失敗しても元のファイルが壊れないようにする。パーミッションは元のファイルに合わせる
+fn write_atomically(path: &Path, contents: &[u8], backup: Option<&str>) -> io::Result<()> {
+    // シンボリックリンクはリンク先のファイルを書き換える
+    let path = fs::canonicalize(path)?;
+    let permissions = fs::metadata(&path)?.permissions();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".grepr-{}.tmp", process::id()));
+    let temp = Path::new(&temp);
+    let result = (|| {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(temp)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::set_permissions(temp, permissions)?;
+        if let Some(suffix) = backup {
+            let mut backup = path.as_os_str().to_owned();
+            backup.push(suffix);
+            fs::copy(&path, backup)?;
+        }
+        fs::rename(temp, &path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(temp);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_with_bom, replace_lines, write_atomically};
+    use crate::matcher::MatcherBuilder;
+
+    #[test]
+    fn test_replace_lines() {
+        let pattern = MatcherBuilder::new().build(&["(o+)$"]).unwrap();
+        let (text, count) = replace_lines(&pattern, b"foo\nbar\nboo", b"[$1]", None);
+        assert_eq!(text, b"f[oo]\nbar\nb[oo]");
+        assert_eq!(count, 2);
+
+        // -mの行数より後の行は置き換えない
+        let (text, count) = replace_lines(&pattern, b"foo\nbar\nboo", b"[$1]", Some(1));
+        assert_eq!(text, b"f[oo]\nbar\nboo");
+        assert_eq!(count, 1);
+
+        // 行末の改行は一致の対象に含めない
+        let pattern = MatcherBuilder::new().build(&["\\s+"]).unwrap();
+        let (text, count) = replace_lines(&pattern, b"a  b\nc\n", b" ", None);
+        assert_eq!(text, b"a b\nc\n");
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn test_encode_with_bom() {
+        assert_eq!(
+            encode_with_bom(encoding_rs::UTF_16LE, b"a\n".to_vec()).unwrap(),
+            b"\xff\xfea\0\n\0"
+        );
+        assert_eq!(
+            encode_with_bom(encoding_rs::UTF_8, b"a\n".to_vec()).unwrap(),
+            b"\xef\xbb\xbfa\n"
+        );
+        // --no-unicodeで文字の途中を置き換えたときなど、UTF-8でなければ書き込まない
+        assert!(encode_with_bom(encoding_rs::UTF_16BE, b"\xe9".to_vec()).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_write_atomically() {
+        use std::{env, fs, os::unix::fs::PermissionsExt};
+
+        let dir = env::temp_dir().join(format!("grepr-rewrite-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("script.sh");
+        fs::write(&path, "old\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+
+        write_atomically(&path, b"new\n", Some(".bak")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o751);
+        assert_eq!(
+            fs::read_to_string(dir.join("script.sh.bak")).unwrap(),
+            "old\n"
+        );
+        // 一時ファイルは残らない
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Result};
+use ignore::types::TypesBuilder;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// ripgrepの既定の種類に加えて追加するファイルの種類
+const EXTRA_TYPES: &[(&str, &str)] = &[("rust", "Cargo.toml")];
+
+/// 組み込みの種類、設定ファイル、`--type-add` の順に定義したファイルの種類を返す
+pub fn types_builder(type_adds: &[String]) -> Result<TypesBuilder> {
+    let config = config_path().filter(|path| path.is_file());
+    build_types(config.as_deref(), type_adds)
+}
+
+/// 組み込みの種類に、`config` のファイルと `type_adds` の定義を加える
+fn build_types(config: Option<&Path>, type_adds: &[String]) -> Result<TypesBuilder> {
+    let mut builder = TypesBuilder::new();
+    builder.add_defaults();
+    for (name, glob) in EXTRA_TYPES {
+        builder.add(name, glob)?;
+    }
+    if let Some(path) = config {
+        add_defs_from_file(&mut builder, path)?;
+    }
+    for def in type_adds {
+        builder
+            .add_def(def)
+            .map_err(|err| anyhow!("--type-add \"{def}\": {err}"))?;
+    }
+    Ok(builder)
+}
+
+/// `$XDG_CONFIG_HOME/grepr/types`、なければ `~/.config/grepr/types`
+fn config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("grepr").join("types"))
+}
+
+/// 1行に1つ `--type-add` と同じ形式で書かれた定義を読み込む。空行と `#` で始まる行は無視する
+fn add_defs_from_file(builder: &mut TypesBuilder, path: &Path) -> Result<()> {
+    let text = fs::read_to_string(path).map_err(|err| anyhow!("{}: {err}", path.display()))?;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        builder
+            .add_def(line)
+            .map_err(|err| anyhow!("{}:{}: {err}", path.display(), i + 1))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add_defs_from_file, build_types};
+    use std::{env, fs};
+
+    #[test]
+    fn test_build_types() {
+        // 実際の設定ファイルは読まない
+        let builder = build_types(None, &["web:*.{html,css}".to_string()]).unwrap();
+        let defs = builder.definitions();
+        let globs = |name: &str| {
+            defs.iter()
+                .find(|def| def.name() == name)
+                .map(|def| def.globs().to_vec())
+                .unwrap_or_default()
+        };
+        assert_eq!(globs("rust"), vec!["*.rs", "Cargo.toml"]);
+        assert_eq!(globs("web"), vec!["*.{html,css}"]);
+
+        // 不正な定義はエラーになる
+        assert!(build_types(None, &["web".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_add_defs_from_file() {
+        let path = env::temp_dir().join(format!("grepr-types-{}", std::process::id()));
+        fs::write(&path, "# comment\n\nproto:*.proto\n").unwrap();
+        let mut builder = build_types(None, &[]).unwrap();
+        add_defs_from_file(&mut builder, &path).unwrap();
+        assert!(builder
+            .definitions()
+            .iter()
+            .any(|def| def.name() == "proto"));
+
+        // エラーにはファイル名と行番号を含める
+        fs::write(&path, "proto:*.proto\nbad\n").unwrap();
+        let err = add_defs_from_file(&mut builder, &path).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with(&format!("{}:2: ", path.display())));
+        fs::remove_file(path).unwrap();
+    }
+}
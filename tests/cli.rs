@@ -11,6 +11,8 @@ const EMPTY: &str = "tests/inputs/empty.txt";
 const FOX: &str = "tests/inputs/fox.txt";
 const NOBODY: &str = "tests/inputs/nobody.txt";
 const INPUTS_DIR: &str = "tests/inputs";
+const NUL: &str = "tests/binary/nul.bin";
+const LATIN1: &str = "tests/binary/latin1.txt";
 
 fn gen_bad_file() -> String {
     loop {
@@ -45,6 +47,26 @@ fn dies_bad_pattern() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn dies_bad_pattern_location() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-e", "foo", "-e", "*foo", FOX])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("-e #2: Invalid pattern \"*foo\""));
+    Command::cargo_bin(PRG)?
+        .args(["-f", "tests/patterns/bad.txt", FOX])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains(
+            "tests/patterns/bad.txt:2: Invalid pattern \"*foo\"",
+        ))
+        .stderr(predicate::str::contains(
+            "repetition operator missing expression",
+        ));
+    Ok(())
+}
+
 #[test]
 fn warns_bad_file() -> Result<()> {
     let bad = gen_bad_file();
@@ -52,10 +74,73 @@ fn warns_bad_file() -> Result<()> {
     Command::cargo_bin(PRG)?
         .args(["foo", &bad])
         .assert()
+        .code(2)
         .stderr(predicate::str::is_match(expected)?);
     Ok(())
 }
 
+#[test]
+fn exit_status() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["The", BUSTLE])
+        .assert()
+        .code(0);
+    Command::cargo_bin(PRG)?
+        .args(["nobody", NOBODY])
+        .assert()
+        .code(1);
+    Command::cargo_bin(PRG)?
+        .args(["*foo", FOX])
+        .assert()
+        .code(2);
+
+    // エラーがあれば一致した行があっても2を返す
+    Command::cargo_bin(PRG)?
+        .args(["The", &gen_bad_file(), FOX])
+        .assert()
+        .code(2)
+        .stdout(predicate::str::contains(FOX));
+    Ok(())
+}
+
+#[test]
+fn quiet() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["--quiet", "The", BUSTLE, FOX])
+        .assert()
+        .code(0)
+        .stdout("");
+    Command::cargo_bin(PRG)?
+        .args(["-q", "nobody", NOBODY])
+        .assert()
+        .code(1)
+        .stdout("");
+
+    // 一致した行があればエラーがあっても0を返す
+    Command::cargo_bin(PRG)?
+        .args(["-q", "The", &gen_bad_file(), FOX])
+        .assert()
+        .code(0)
+        .stdout("");
+    Ok(())
+}
+
+#[test]
+fn no_messages() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["--no-messages", "The", &gen_bad_file(), FOX])
+        .assert()
+        .code(2)
+        .stderr("")
+        .stdout(predicate::str::contains(FOX));
+    Command::cargo_bin(PRG)?
+        .args(["-s", "fox", INPUTS_DIR])
+        .assert()
+        .code(2)
+        .stderr("");
+    Ok(())
+}
+
 fn run(args: &[&str], expected_file: &str) -> Result<()> {
     let windows_file = format!("{}.windows", expected_file);
     let expected_file = if os_type().unwrap() == "Windows" && Path::new(&windows_file).is_file() {
@@ -149,6 +234,160 @@ fn recursive_insensitive() -> Result<()> {
     )
 }
 
+#[test]
+fn recursive_include() -> Result<()> {
+    run(
+        &["-r", "--include", "f*.txt", "dog", INPUTS_DIR],
+        "tests/expected/dog.recursive.include",
+    )
+}
+
+#[test]
+fn exclude_command_line_files() -> Result<()> {
+    run(
+        &["--exclude", "*.txt", "The", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.capitalized.exclude",
+    )
+}
+
+#[test]
+fn recursive_exclude_dir() -> Result<()> {
+    run(
+        &["-r", "--exclude-dir", "inputs", "dog", INPUTS_DIR],
+        "tests/expected/dog.recursive.exclude_dir",
+    )
+}
+
+#[test]
+fn recursive_glob() -> Result<()> {
+    run(
+        &[
+            "-r",
+            "-g",
+            "*.txt",
+            "-g",
+            "!{bustle,empty,nobody}.txt",
+            "dog",
+            INPUTS_DIR,
+        ],
+        "tests/expected/dog.recursive",
+    )
+}
+
+#[test]
+fn recursive_type() -> Result<()> {
+    run(
+        &["-r", "--type", "txt", "dog", INPUTS_DIR],
+        "tests/expected/dog.recursive",
+    )
+}
+
+#[test]
+fn recursive_type_not() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-r", "-T", "txt", "dog", INPUTS_DIR])
+        .assert()
+        .code(1)
+        .stdout("");
+    Ok(())
+}
+
+#[test]
+fn recursive_type_add() -> Result<()> {
+    run(
+        &[
+            "-r",
+            "--type-add",
+            "dog:f*.txt",
+            "-t",
+            "dog",
+            "dog",
+            INPUTS_DIR,
+        ],
+        "tests/expected/dog.recursive",
+    )
+}
+
+#[test]
+fn recursive_type_config() -> Result<()> {
+    let expected = fs::read_to_string("tests/expected/dog.recursive")?;
+    Command::cargo_bin(PRG)?
+        .env("XDG_CONFIG_HOME", "tests/config")
+        .args(["-r", "-t", "fox", "dog", INPUTS_DIR])
+        .assert()
+        .stdout(expected);
+    Ok(())
+}
+
+#[test]
+fn dies_unknown_type() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-r", "-t", "nosuchtype", "dog", INPUTS_DIR])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("nosuchtype"));
+    Ok(())
+}
+
+#[test]
+fn type_list() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .arg("--type-list")
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("\nrust: *.rs, Cargo.toml\n"));
+    Ok(())
+}
+
+#[test]
+fn recursive_sort_path() -> Result<()> {
+    run(
+        &["--sort", "path", "-j", "4", "-rn", "the", INPUTS_DIR],
+        "tests/expected/the.recursive.line_number.sorted",
+    )
+}
+
+#[test]
+fn recursive_sort_path_context() -> Result<()> {
+    run(
+        &["--sort", "path", "-rn", "-C", "1", "the", INPUTS_DIR],
+        "tests/expected/the.recursive.line_number.context.sorted",
+    )
+}
+
+#[test]
+fn recursive_threads() -> Result<()> {
+    let expected = fs::read_to_string("tests/expected/the.recursive.line_number.context.sorted")?;
+    let output = Command::cargo_bin(PRG)?
+        .args(["--threads", "4", "-rn", "-C", "1", "the", INPUTS_DIR])
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout)?.replace('\\', "/");
+
+    // ファイルごとの出力は区切りで分けられ、他のファイルの出力と混ざらない
+    let mut actual: Vec<_> = stdout.split("--\n").collect();
+    let mut expected: Vec<_> = expected.split("--\n").collect();
+    actual.sort();
+    expected.sort();
+    assert_eq!(actual, expected);
+    Ok(())
+}
+
+#[test]
+fn threads_multiple_files() -> Result<()> {
+    let expected = fs::read_to_string("tests/expected/all.the.capitalized.count")?;
+    let output = Command::cargo_bin(PRG)?
+        .args(["-j", "0", "-c", "The", BUSTLE, EMPTY, FOX, NOBODY])
+        .output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    let mut actual: Vec<_> = stdout.lines().collect();
+    let mut expected: Vec<_> = expected.lines().collect();
+    actual.sort();
+    expected.sort();
+    assert_eq!(actual, expected);
+    Ok(())
+}
+
 #[test]
 fn sensitive_count_capital() -> Result<()> {
     run(
@@ -214,6 +453,999 @@ fn warns_dir_not_recursive() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn line_number() -> Result<()> {
+    run(
+        &["--line-number", "The", BUSTLE],
+        "tests/expected/bustle.txt.the.capitalized.line_number",
+    )
+}
+
+#[test]
+fn byte_offset() -> Result<()> {
+    run(
+        &["--byte-offset", "The", BUSTLE],
+        "tests/expected/bustle.txt.the.capitalized.byte_offset",
+    )
+}
+
+#[test]
+fn line_number_byte_offset_multiple() -> Result<()> {
+    run(
+        &["-nbi", "the", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.lowercase.insensitive.line_number.byte_offset",
+    )
+}
+
+#[test]
+fn after_context() -> Result<()> {
+    run(
+        &["--after-context", "1", "the", BUSTLE],
+        "tests/expected/bustle.txt.the.lowercase.after_context",
+    )
+}
+
+#[test]
+fn before_context() -> Result<()> {
+    run(
+        &["-B", "2", "The", BUSTLE],
+        "tests/expected/bustle.txt.the.capitalized.before_context",
+    )
+}
+
+#[test]
+fn context_multiple() -> Result<()> {
+    run(
+        &["-n", "-C", "1", "The", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.capitalized.context.line_number",
+    )
+}
+
+#[test]
+fn context_zero() -> Result<()> {
+    run(
+        &["-A", "0", "The", BUSTLE],
+        "tests/expected/bustle.txt.the.capitalized.after_context.zero",
+    )
+}
+
+#[test]
+fn only_matching_context() -> Result<()> {
+    run(
+        &["-on", "-C", "1", "The", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.capitalized.only_matching.context.line_number",
+    )
+}
+
+#[test]
+fn only_matching_invert_context() -> Result<()> {
+    // -vでは一致する文脈行の一致した部分を出力する
+    run(
+        &["-onv", "-C", "1", "the", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.lowercase.only_matching.invert.context.line_number",
+    )
+}
+
+#[test]
+fn only_matching() -> Result<()> {
+    run(
+        &["--only-matching", "the", BUSTLE],
+        "tests/expected/bustle.txt.the.lowercase.only_matching",
+    )
+}
+
+#[test]
+fn only_matching_multiple() -> Result<()> {
+    run(
+        &["-onbi", "the", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.lowercase.insensitive.only_matching.line_number.byte_offset",
+    )
+}
+
+#[test]
+fn only_matching_invert() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-ov", "the", FOX])
+        .assert()
+        .stdout("");
+    Ok(())
+}
+
+#[test]
+fn column() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["--column", "-o", "o", FOX])
+        .assert()
+        .stdout("1:13:o\n1:18:o\n1:27:o\n1:42:o\n");
+    Ok(())
+}
+
+#[test]
+fn color_always() -> Result<()> {
+    run(
+        &[
+            "--color=always",
+            "-n",
+            "-C",
+            "1",
+            "the",
+            BUSTLE,
+            EMPTY,
+            FOX,
+            NOBODY,
+        ],
+        "tests/expected/all.the.lowercase.context.line_number.color",
+    )
+}
+
+#[test]
+fn color_invert_context() -> Result<()> {
+    run(
+        &["--color=always", "-v", "-C", "1", "the", BUSTLE],
+        "tests/expected/bustle.txt.the.lowercase.invert.context.color",
+    )
+}
+
+#[test]
+fn color_grep_colors() -> Result<()> {
+    let expected =
+        fs::read_to_string("tests/expected/all.the.lowercase.only_matching.grep_colors")?;
+    Command::cargo_bin(PRG)?
+        .env_remove("GREPR_COLORS")
+        .env("GREP_COLORS", "ms=04;32:fn=34:ne")
+        .args(["--color=always", "-o", "the", BUSTLE, EMPTY, FOX, NOBODY])
+        .assert()
+        .stdout(expected);
+    Ok(())
+}
+
+#[test]
+fn color_grep_colors_lines() -> Result<()> {
+    // slとcxの色の上に一致した部分の色を重ねる
+    let expected = fs::read_to_string(
+        "tests/expected/all.the.lowercase.context.line_number.grep_colors_lines",
+    )?;
+    Command::cargo_bin(PRG)?
+        .env_remove("GREPR_COLORS")
+        .env("GREP_COLORS", "sl=1:cx=2:ms=04:mc=05")
+        .args([
+            "--color=always",
+            "-n",
+            "-C",
+            "1",
+            "the",
+            BUSTLE,
+            EMPTY,
+            FOX,
+            NOBODY,
+        ])
+        .assert()
+        .stdout(expected);
+    Ok(())
+}
+
+#[test]
+fn color_auto_not_tty() -> Result<()> {
+    run(
+        &["--color", "The", BUSTLE],
+        "tests/expected/bustle.txt.the.capitalized",
+    )
+}
+
+#[test]
+fn files_with_matches() -> Result<()> {
+    run(
+        &["--files-with-matches", "The", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.capitalized.files_with_matches",
+    )
+}
+
+#[test]
+fn files_without_match() -> Result<()> {
+    run(
+        &["-L", "The", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.capitalized.files_without_match",
+    )
+}
+
+#[test]
+fn files_with_matches_invert() -> Result<()> {
+    let expected = fs::read_to_string("tests/expected/the.recursive.invert.files_with_matches")?;
+    let mut expected: Vec<_> = expected.lines().collect();
+    expected.sort();
+    let output = Command::cargo_bin(PRG)?
+        .args(["-rlv", "the", INPUTS_DIR])
+        .output()?;
+    let stdout = String::from_utf8(output.stdout)?.replace('\\', "/");
+    let mut actual: Vec<_> = stdout.lines().collect();
+    actual.sort();
+    assert_eq!(actual, expected);
+    Ok(())
+}
+
+#[test]
+fn dies_files_with_and_without_match() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-l", "-L", "The", BUSTLE])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+#[test]
+fn fixed_strings() -> Result<()> {
+    run(
+        &["--fixed-strings", "-on", ".", BUSTLE],
+        "tests/expected/bustle.txt.dot.fixed_strings",
+    )
+}
+
+#[test]
+fn fixed_strings_insensitive() -> Result<()> {
+    run(
+        &["-Fi", "nobody!", NOBODY],
+        "tests/expected/nobody.txt.fixed_strings.insensitive",
+    )
+}
+
+#[test]
+fn fixed_strings_multiple_invert() -> Result<()> {
+    run(
+        &["-Fv", "the\nThe", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.fixed_strings.invert",
+    )
+}
+
+#[test]
+fn regexp_multiple() -> Result<()> {
+    run(
+        &["-e", "fox", "--regexp", "Frog", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.fox.frog.regexp",
+    )
+}
+
+#[test]
+fn pattern_file() -> Result<()> {
+    run(
+        &[
+            "--file",
+            "tests/patterns/the_fox.txt",
+            BUSTLE,
+            EMPTY,
+            FOX,
+            NOBODY,
+        ],
+        "tests/expected/all.the_fox.pattern_file",
+    )
+}
+
+#[test]
+fn pattern_file_stdin() -> Result<()> {
+    let expected = fs::read_to_string("tests/expected/all.the_fox.pattern_file")?;
+    Command::cargo_bin(PRG)?
+        .args(["-f", "-", BUSTLE, EMPTY, FOX, NOBODY])
+        .write_stdin("The\nfox\n")
+        .assert()
+        .stdout(expected);
+    Ok(())
+}
+
+#[test]
+fn word_regexp() -> Result<()> {
+    run(
+        &["--word-regexp", "the", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.the.lowercase.word_regexp",
+    )
+}
+
+#[test]
+fn word_regexp_only_matching() -> Result<()> {
+    run(
+        &["-wo", "Nobody", NOBODY],
+        "tests/expected/nobody.txt.word_regexp.only_matching",
+    )
+}
+
+#[test]
+fn line_regexp() -> Result<()> {
+    run(
+        &["-xi", "the bustle in a house", BUSTLE, EMPTY, FOX, NOBODY],
+        "tests/expected/all.bustle.line_regexp",
+    )
+}
+
+#[test]
+fn end_of_line() -> Result<()> {
+    run(&["dog.$", FOX], "tests/expected/fox.txt.dog.end_of_line")
+}
+
+#[test]
+fn binary_file_matches() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["foo", NUL])
+        .assert()
+        .code(0)
+        .stdout("Binary file tests/binary/nul.bin matches\n");
+    Command::cargo_bin(PRG)?
+        .args(["-c", "foo", NUL])
+        .assert()
+        .stdout("2\n");
+    Command::cargo_bin(PRG)?
+        .args(["nothing", NUL])
+        .assert()
+        .code(1)
+        .stdout("");
+    Ok(())
+}
+
+#[test]
+fn binary_files_text() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["--text", "foo", NUL])
+        .assert()
+        .stdout("foo\0bar\nbaz foo\n");
+    Command::cargo_bin(PRG)?
+        .args(["--binary-files=text", "-n", "baz", NUL])
+        .assert()
+        .stdout("2:baz foo\n");
+    Ok(())
+}
+
+#[test]
+fn binary_files_without_match() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-I", "foo", NUL, FOX])
+        .assert()
+        .code(1)
+        .stdout("");
+    Command::cargo_bin(PRG)?
+        .args(["--binary-files=without-match", "-l", "o", NUL, FOX])
+        .assert()
+        .code(0)
+        .stdout(format!("{FOX}\n"));
+    Ok(())
+}
+
+#[test]
+fn invalid_utf8() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-n", "foo", LATIN1])
+        .assert()
+        .code(0)
+        .stdout(&b"1:caf\xe9 foo\n"[..]);
+    Ok(())
+}
+
+#[test]
+fn no_unicode() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args([r"\xE9", LATIN1])
+        .assert()
+        .code(1)
+        .stdout("");
+    Command::cargo_bin(PRG)?
+        .args(["--no-unicode", "-o", r"caf\xE9", LATIN1])
+        .assert()
+        .code(0)
+        .stdout(&b"caf\xe9\n"[..]);
+    Command::cargo_bin(PRG)?
+        .args(["--no-unicode", "--color=always", r"\xE9", LATIN1])
+        .assert()
+        .stdout(&b"caf\x1b[01;31m\x1b[K\xe9\x1b[m\x1b[K foo\n"[..]);
+    Ok(())
+}
+
+#[test]
+fn encoding_bom() -> Result<()> {
+    for file in ["utf16le.txt", "utf16be.txt", "utf8bom.txt"] {
+        Command::cargo_bin(PRG)?
+            .args(["-n", "café", &format!("tests/encoding/{file}")])
+            .assert()
+            .code(0)
+            .stdout("1:café foo\n");
+    }
+    Command::cargo_bin(PRG)?
+        .args(["-c", "a", "tests/encoding/utf16le.txt"])
+        .assert()
+        .stdout("2\n");
+    Ok(())
+}
+
+#[test]
+fn encoding_forced() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-E", "shift_jis", "日本", "tests/encoding/sjis.txt"])
+        .assert()
+        .code(0)
+        .stdout("日本語 foo\n");
+    Command::cargo_bin(PRG)?
+        .args([
+            "--encoding",
+            "euc-jp",
+            "-o",
+            "本.",
+            "tests/encoding/eucjp.txt",
+        ])
+        .assert()
+        .stdout("本語\n");
+    Command::cargo_bin(PRG)?
+        .args(["--encoding=latin1", "é", LATIN1])
+        .assert()
+        .stdout("café foo\n");
+    Command::cargo_bin(PRG)?
+        .args(["-E", "klingon", "foo", FOX])
+        .assert()
+        .code(2)
+        .stderr("klingon: Unknown encoding\n");
+    Ok(())
+}
+
+#[test]
+fn search_zip() -> Result<()> {
+    for file in [
+        "app.log.gz",
+        "app.log.bz2",
+        "app.log.xz",
+        "app.log.zst",
+        "rotated",
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(["-z", "fox", &format!("tests/zip/{file}")])
+            .assert()
+            .code(0)
+            .stdout("The quick brown fox\n");
+    }
+    Command::cargo_bin(PRG)?
+        .args(["fox", "tests/zip/app.log.gz"])
+        .assert()
+        .code(1);
+    Ok(())
+}
+
+#[test]
+fn search_zip_error() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-z", "foo", "tests/zip/broken.log.gz"])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::starts_with("tests/zip/broken.log.gz: "));
+    Ok(())
+}
+
+#[test]
+fn search_zip_recursive() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args([
+            "-rz",
+            "--sort=path",
+            "--include=*.log",
+            "--exclude=broken*",
+            "-c",
+            "dog",
+            "tests/zip",
+        ])
+        .assert()
+        .code(0)
+        .stdout(
+            "tests/zip/app.log.bz2:1\n\
+             tests/zip/app.log.gz:1\n\
+             tests/zip/app.log.xz:1\n\
+             tests/zip/app.log.zst:1\n",
+        );
+    Ok(())
+}
+
+#[test]
+fn archives() -> Result<()> {
+    for file in ["artifacts.tar", "artifacts.tar.gz"] {
+        Command::cargo_bin(PRG)?
+            .args(["--archives", "-n", "fox", &format!("tests/archives/{file}")])
+            .assert()
+            .code(0)
+            .stdout(format!(
+                "tests/archives/{file}:docs/readme.txt:1:The quick brown fox\n"
+            ));
+    }
+    Command::cargo_bin(PRG)?
+        .args(["fox", "tests/archives/artifacts.tar"])
+        .assert()
+        .stdout("Binary file tests/archives/artifacts.tar matches\n");
+    Ok(())
+}
+
+#[test]
+fn archives_nested() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["--archives", "fox", "tests/archives/artifacts.zip"])
+        .assert()
+        .code(0)
+        .stdout(
+            "tests/archives/artifacts.zip:docs/readme.txt:The quick brown fox\n\
+             tests/archives/artifacts.zip:nested.tar.gz:inner.txt:a fox in a nested archive\n",
+        );
+    Command::cargo_bin(PRG)?
+        .args([
+            "--archives",
+            "--archive-depth=1",
+            "-c",
+            "fox",
+            "tests/archives/artifacts.zip",
+        ])
+        .assert()
+        .stdout(
+            "tests/archives/artifacts.zip:docs/readme.txt:1\n\
+             tests/archives/artifacts.zip:notes.txt:0\n\
+             tests/archives/artifacts.zip:nested.tar.gz:inner.txt:1\n",
+        );
+    // 0なら中のアーカイブは開かず、バイト列としても検索しない
+    Command::cargo_bin(PRG)?
+        .args([
+            "--archives",
+            "--archive-depth=0",
+            "-c",
+            "fox",
+            "tests/archives/artifacts.zip",
+        ])
+        .assert()
+        .stdout(
+            "tests/archives/artifacts.zip:docs/readme.txt:1\n\
+             tests/archives/artifacts.zip:notes.txt:0\n",
+        );
+    Ok(())
+}
+
+#[test]
+fn archives_recursive() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args([
+            "-r",
+            "--archives",
+            "--sort=path",
+            "--include=notes.*",
+            "-c",
+            "dog",
+            "tests/archives",
+        ])
+        .assert()
+        .code(0)
+        .stdout(
+            "tests/archives/artifacts.tar:notes.txt:1\n\
+             tests/archives/artifacts.tar.gz:notes.txt:1\n\
+             tests/archives/artifacts.zip:notes.txt:1\n",
+        );
+    Ok(())
+}
+
+#[test]
+fn mmap() -> Result<()> {
+    let cases: [&[&str]; 5] = [
+        &["-n", "The", BUSTLE],
+        &["-C", "1", "-n", "the", BUSTLE],
+        &["-v", "-b", "the", BUSTLE, FOX],
+        &["-c", "-i", "the", BUSTLE, EMPTY, FOX, NOBODY],
+        &["-ow", "[a-z]+", NOBODY],
+    ];
+    for args in cases {
+        let expected = Command::cargo_bin(PRG)?.args(args).output()?;
+        for flag in ["--mmap", "--no-mmap", "--mmap --no-mmap"] {
+            Command::cargo_bin(PRG)?
+                .args(flag.split(' '))
+                .args(args)
+                .assert()
+                .code(expected.status.code().unwrap())
+                .stdout(expected.stdout.clone());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn mmap_stdin() -> Result<()> {
+    let input = fs::read_to_string(BUSTLE)?;
+    let expected = fs::read_to_string("tests/expected/bustle.txt.the.capitalized")?;
+    Command::cargo_bin(PRG)?
+        .args(["--mmap", "The"])
+        .write_stdin(input)
+        .assert()
+        .stdout(expected);
+    Ok(())
+}
+
+/// JSON Linesの出力を1行ずつ読み込む
+fn json_events(args: &[&str]) -> Result<Vec<serde_json::Value>> {
+    let output = Command::cargo_bin(PRG)?.args(args).output()?;
+    let stdout = String::from_utf8(output.stdout)?;
+    Ok(stdout
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?)
+}
+
+#[test]
+fn json() -> Result<()> {
+    let events = json_events(&["--json", "fox", FOX, EMPTY])?;
+    let types: Vec<_> = events.iter().map(|event| &event["type"]).collect();
+    assert_eq!(types, ["begin", "match", "end", "begin", "end", "summary"]);
+    assert_eq!(
+        events[1]["data"],
+        serde_json::json!({
+            "path": { "text": FOX },
+            "lines": { "text": "The quick brown fox jumps over the lazy dog.\n" },
+            "line_number": 1,
+            "absolute_offset": 0,
+            "submatches": [{ "match": { "text": "fox" }, "start": 16, "end": 19 }],
+        })
+    );
+    assert_eq!(
+        events[2]["data"]["stats"],
+        serde_json::json!({ "matched_lines": 1, "matches": 1 })
+    );
+    assert_eq!(
+        events[5]["data"]["stats"],
+        serde_json::json!({ "searches": 2, "searches_with_match": 1, "matched_lines": 1 })
+    );
+    Ok(())
+}
+
+#[test]
+fn json_context() -> Result<()> {
+    let events = json_events(&["--json", "-B1", "morning", BUSTLE])?;
+    let lines: Vec<_> = events
+        .iter()
+        .filter(|event| event["type"] == "match" || event["type"] == "context")
+        .map(|event| (&event["type"], &event["data"]["line_number"]))
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], (&"context".into(), &1.into()));
+    assert_eq!(lines[1], (&"match".into(), &2.into()));
+    Ok(())
+}
+
+#[test]
+fn json_bytes() -> Result<()> {
+    let events = json_events(&["--json", "--no-unicode", r"caf\xE9", LATIN1])?;
+    assert_eq!(
+        events[1]["data"]["lines"],
+        serde_json::json!({ "bytes": "Y2Fm6SBmb28K" })
+    );
+    assert_eq!(
+        events[1]["data"]["submatches"][0]["match"],
+        serde_json::json!({ "bytes": "Y2Fm6Q==" })
+    );
+    Command::cargo_bin(PRG)?
+        .args(["--json", "-c", "foo", FOX])
+        .assert()
+        .code(2);
+    // 置き換えた結果はイベントに含められないので、-pとは合わせて使えない
+    Command::cargo_bin(PRG)?
+        .args(["--json", "-p", "cat", "fox", FOX])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("cannot be used with"));
+    Ok(())
+}
+
+#[test]
+fn replace() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["--replace", "[$2 $1]", r"(\w+) (fox)", FOX])
+        .assert()
+        .code(0)
+        .stdout("The quick [fox brown] jumps over the lazy dog.\n");
+    Command::cargo_bin(PRG)?
+        .args(["-p", "$$${animal}", r"(?P<animal>fox|dog)", FOX])
+        .assert()
+        .stdout("The quick brown $fox jumps over the lazy $dog.\n");
+    Command::cargo_bin(PRG)?
+        .args(["-F", "-p", "<$0>", "the", FOX])
+        .assert()
+        .stdout("The quick brown fox jumps over <the> lazy dog.\n");
+    Ok(())
+}
+
+#[test]
+fn replace_only_matching() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-o", "-b", "-p", "${1}ed", r"(jump)s", FOX])
+        .assert()
+        .code(0)
+        .stdout("20:jumped\n");
+    Ok(())
+}
+
+#[test]
+fn write() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("grepr-write-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let path = dir.join("fox.txt");
+    fs::copy(FOX, &path)?;
+    let path = path.display().to_string();
+
+    // --dry-runは書き換えずに差分を出力する
+    Command::cargo_bin(PRG)?
+        .args(["--write", "--dry-run", "-p", "cat", "fox", &path])
+        .assert()
+        .code(0)
+        .stdout(format!(
+            "--- {path}\n+++ {path}\n@@ -1 +1 @@\n\
+             -The quick brown fox jumps over the lazy dog.\n\
+             +The quick brown cat jumps over the lazy dog.\n"
+        ));
+    assert_eq!(fs::read_to_string(&path)?, fs::read_to_string(FOX)?);
+
+    Command::cargo_bin(PRG)?
+        .args(["--write", "--backup", ".orig", "-p", "cat", "fox", &path])
+        .assert()
+        .code(0)
+        .stdout("");
+    assert_eq!(
+        fs::read_to_string(&path)?,
+        "The quick brown cat jumps over the lazy dog.\n"
+    );
+    assert_eq!(
+        fs::read_to_string(format!("{path}.orig"))?,
+        fs::read_to_string(FOX)?
+    );
+
+    // 一致しなければ書き換えない
+    Command::cargo_bin(PRG)?
+        .args(["--write", "-p", "cat", "fox", &path])
+        .assert()
+        .code(1);
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn write_bom() -> Result<()> {
+    // BOMの付いたファイルも検索と同じく変換して置き換え、元のエンコーディングで書き込む
+    let dir = std::env::temp_dir().join(format!("grepr-write-bom-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let utf16le: Vec<u8> = "\u{feff}X foo\nbar\n"
+        .encode_utf16()
+        .flat_map(u16::to_le_bytes)
+        .collect();
+    for (file, expected) in [
+        ("utf8bom.txt", "\u{feff}X foo\nbar\n".as_bytes()),
+        ("utf16le.txt", &utf16le),
+    ] {
+        let path = dir.join(file);
+        fs::copy(format!("tests/encoding/{file}"), &path)?;
+        Command::cargo_bin(PRG)?
+            .args(["--write", "-p", "X", "^café"])
+            .arg(&path)
+            .assert()
+            .code(0);
+        assert_eq!(fs::read(&path)?, expected);
+    }
+    fs::remove_dir_all(dir)?;
+    Ok(())
+}
+
+#[test]
+fn dies_write() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["--write", "fox", FOX])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("--replace"));
+    Command::cargo_bin(PRG)?
+        .args(["--write", "-p", "cat", "fox"])
+        .write_stdin("fox\n")
+        .assert()
+        .code(2)
+        .stderr("-: Cannot rewrite standard input\n");
+    // 出力を変える指定は書き換えと合わせて使えない
+    for flag in [
+        "-q",
+        "-c",
+        "-l",
+        "-L",
+        "-o",
+        "-n",
+        "-b",
+        "--column",
+        "-A1",
+        "-B1",
+        "-C1",
+        "--color=always",
+    ] {
+        Command::cargo_bin(PRG)?
+            .args(["--write", "-p", "cat", flag, "fox", FOX])
+            .assert()
+            .code(2)
+            .stderr(predicate::str::contains("cannot be used with"));
+    }
+    Ok(())
+}
+
+#[test]
+fn write_max_count() -> Result<()> {
+    // -mの行数まで置き換える
+    Command::cargo_bin(PRG)?
+        .args(["--write", "--dry-run", "-m", "1", "-p", "A", "The", BUSTLE])
+        .assert()
+        .code(0)
+        .stdout(format!(
+            "--- {BUSTLE}\n+++ {BUSTLE}\n@@ -1,4 +1,4 @@\n\
+             -The bustle in a house\n\
+             +A bustle in a house\n \
+             The morning after death\n \
+             Is solemnest of industries\n \
+             Enacted upon earth,—\n"
+        ));
+    Ok(())
+}
+
+#[test]
+fn write_binary() -> Result<()> {
+    // 一致したバイナリファイルは書き換えずに知らせる
+    Command::cargo_bin(PRG)?
+        .args(["--write", "--dry-run", "-p", "qux", "foo", NUL])
+        .assert()
+        .code(0)
+        .stdout("")
+        .stderr(format!(
+            "{NUL}: Binary file matches; not rewritten (use -a to rewrite)\n"
+        ));
+    Command::cargo_bin(PRG)?
+        .args(["--write", "--dry-run", "-I", "-p", "qux", "foo", NUL])
+        .assert()
+        .code(1)
+        .stderr("");
+    Command::cargo_bin(PRG)?
+        .args(["--write", "--dry-run", "-a", "-p", "qux", "baz", NUL])
+        .assert()
+        .code(0)
+        .stdout(format!(
+            "--- {NUL}\n+++ {NUL}\n@@ -1,2 +1,2 @@\n foo\0bar\n-baz foo\n+qux foo\n"
+        ));
+    Ok(())
+}
+
+#[test]
+fn max_count() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["-m", "2", "The", BUSTLE])
+        .assert()
+        .code(0)
+        .stdout("The bustle in a house\nThe morning after death\n");
+    Command::cargo_bin(PRG)?
+        .args(["-m", "2", "-c", "The", BUSTLE])
+        .assert()
+        .stdout("2\n");
+    // -vでは一致しない行を数える
+    Command::cargo_bin(PRG)?
+        .args(["-m", "2", "-v", "The", BUSTLE])
+        .assert()
+        .stdout("Is solemnest of industries\nEnacted upon earth,—\n");
+    Command::cargo_bin(PRG)?
+        .args(["-m", "0", "The", BUSTLE])
+        .assert()
+        .code(1)
+        .stdout("");
+    Ok(())
+}
+
+#[test]
+fn max_count_context() -> Result<()> {
+    // 上限に達した後の行は一致しても文脈行として出力する
+    Command::cargo_bin(PRG)?
+        .args(["-n", "-m", "1", "-A", "1", "The", BUSTLE])
+        .assert()
+        .code(0)
+        .stdout("1:The bustle in a house\n2-The morning after death\n");
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn max_count_stdin() -> Result<()> {
+    // 読み終えなかった標準入力は、最後に一致した行の直後から続けて読める
+    let mut file = fs::File::open(BUSTLE)?;
+    let output = std::process::Command::new(assert_cmd::cargo::cargo_bin(PRG))
+        .args(["-m", "1", "The"])
+        .stdin(file.try_clone()?)
+        .output()?;
+    assert_eq!(String::from_utf8(output.stdout)?, "The bustle in a house\n");
+    let rest = std::io::read_to_string(&mut file)?;
+    let expected = fs::read_to_string(BUSTLE)?;
+    assert_eq!(rest, expected["The bustle in a house\n".len()..]);
+    Ok(())
+}
+
+#[test]
+fn multiline() -> Result<()> {
+    let input = "fn foo(\n    arg: u32,\n) {}\n";
+    Command::cargo_bin(PRG)?
+        .args(["-n", "-U", r"fn foo\(\n\s*arg"])
+        .write_stdin(input)
+        .assert()
+        .code(0)
+        .stdout("1:fn foo(\n2:    arg: u32,\n");
+    // 1行ずつ検索すると改行をまたいで一致しない
+    Command::cargo_bin(PRG)?
+        .args([r"fn foo\(\n\s*arg"])
+        .write_stdin(input)
+        .assert()
+        .code(1);
+    Command::cargo_bin(PRG)?
+        .args(["-U", "--multiline-dotall", "-c", "foo.*arg"])
+        .write_stdin(input)
+        .assert()
+        .stdout("2\n");
+    Command::cargo_bin(PRG)?
+        .args(["-U", "-c", "foo.*arg"])
+        .write_stdin(input)
+        .assert()
+        .code(1)
+        .stdout("0\n");
+    Ok(())
+}
+
+#[test]
+fn multiline_context() -> Result<()> {
+    let expected =
+        "1:The bustle in a house\n2:The morning after death\n3-Is solemnest of industries\n";
+    for mmap in ["--mmap", "--no-mmap"] {
+        Command::cargo_bin(PRG)?
+            .args(["-U", mmap, "-n", "-A", "1", r"house\nThe", BUSTLE])
+            .assert()
+            .code(0)
+            .stdout(expected);
+    }
+    Ok(())
+}
+
+#[test]
+fn multiline_json() -> Result<()> {
+    // 行をまたぐ一致は、それぞれの行にかかる部分を返す
+    let events = json_events(&["--json", "-U", r"house\nThe", BUSTLE])?;
+    let submatches: Vec<_> = events
+        .iter()
+        .filter(|event| event["type"] == "match")
+        .map(|event| &event["data"]["submatches"])
+        .collect();
+    assert_eq!(
+        submatches,
+        [
+            &serde_json::json!([{ "match": { "text": "house" }, "start": 16, "end": 21 }]),
+            &serde_json::json!([{ "match": { "text": "The" }, "start": 0, "end": 3 }]),
+        ]
+    );
+    assert_eq!(
+        events[3]["data"]["stats"],
+        serde_json::json!({ "matched_lines": 2, "matches": 2 })
+    );
+    Ok(())
+}
+
+#[test]
+fn multiline_color() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .env_remove("GREPR_COLORS")
+        .env_remove("GREP_COLORS")
+        .args(["-U", "--color=always", r"house\nThe", BUSTLE])
+        .assert()
+        .code(0)
+        .stdout(
+            "The bustle in a \x1b[01;31m\x1b[Khouse\x1b[m\x1b[K\n\
+             \x1b[01;31m\x1b[KThe\x1b[m\x1b[K morning after death\n",
+        );
+    Ok(())
+}
+
+#[test]
+fn dies_multiline_dotall() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .args(["--multiline-dotall", "foo", FOX])
+        .assert()
+        .code(2)
+        .stderr(predicate::str::contains("--multiline"));
+    Ok(())
+}
+
 #[test]
 fn stdin() -> Result<()> {
     let input = fs::read_to_string(BUSTLE)?;